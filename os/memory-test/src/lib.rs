@@ -0,0 +1,36 @@
+//! 在本机上运行内存管理模块中与硬件无关的单元测试
+//!
+//! 内核只能编译到 riscv64，无法直接运行 `cargo test`。这里通过 `#[path]` 引入 `os/src/memory` 中
+//! 不涉及页表、物理帧和特权指令的文件，按照它们在内核中的路径重新组织，使其中的 `#[cfg(test)]` 测试可以在本机上运行。
+//! 需要真实的页表和物理帧的检查在 QEMU 中进行，见内核的 `memory::self_test`。
+
+// 内核的其他部分才会用到这些文件中的大多数函数
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[path = "../../src/memory/address.rs"]
+mod address;
+#[path = "../../src/memory/config.rs"]
+mod config;
+#[path = "../../src/memory/mapping/page_table_entry.rs"]
+mod page_table_entry;
+#[path = "../../src/memory/range.rs"]
+mod range;
+#[path = "../../src/memory/mapping/segment.rs"]
+mod segment;
+
+/// 与内核中的 `crate::memory` 对应，使被引入的文件中的路径保持不变
+mod memory {
+    pub(crate) use super::{address, config, range};
+
+    pub type MemoryResult<T> = Result<T, &'static str>;
+
+    pub mod mapping {
+        pub(crate) use crate::page_table_entry::Flags;
+    }
+}
+
+/// `config.rs` 引用了 linker.ld 中定义的符号，这里只需要让链接通过
+#[no_mangle]
+extern "C" fn kernel_end() {}
@@ -0,0 +1,208 @@
+//! 提供位图实现的分配器 [`BitmapAllocator`]
+
+use super::{Allocator, ContiguousAllocator};
+use alloc::{vec, vec::Vec};
+
+/// 使用位图实现分配器
+///
+/// 每一位表示一个元素，1 表示已分配。每次分配从上次分配的位置开始向后查找，
+/// 使相邻的分配尽量落在相邻的位置；查找连续区间时整字跳过已满的部分。
+pub struct BitmapAllocator {
+    /// 位图本身，容量之外的位始终为 1
+    bits: Vec<u64>,
+    /// 下一次分配开始查找的字下标
+    hint: usize,
+}
+
+impl Allocator for BitmapAllocator {
+    fn new(capacity: usize) -> Self {
+        let mut bits = vec![0u64; (capacity + 63) / 64];
+        // 将尾部超出范围的位标记为已分配
+        if capacity % 64 != 0 {
+            *bits.last_mut().unwrap() = !0u64 << (capacity % 64);
+        }
+        Self { bits, hint: 0 }
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        let len = self.bits.len();
+        for i in (self.hint..len).chain(0..self.hint) {
+            if self.bits[i] != !0 {
+                let bit = (!self.bits[i]).trailing_zeros() as usize;
+                self.bits[i] |= 1 << bit;
+                self.hint = i;
+                return Some(i * 64 + bit);
+            }
+        }
+        None
+    }
+
+    fn dealloc(&mut self, index: usize) {
+        assert!(self.is_allocated(index), "dealloc a free element");
+        self.bits[index / 64] &= !(1 << (index % 64));
+    }
+
+    fn reserve(&mut self, index: usize) -> bool {
+        if self.is_allocated(index) {
+            return false;
+        }
+        self.bits[index / 64] |= 1 << (index % 64);
+        true
+    }
+}
+
+impl ContiguousAllocator for BitmapAllocator {
+    fn alloc_contiguous(&mut self, count: usize, align: usize, offset: usize) -> Option<usize> {
+        assert!(count > 0 && align > 0);
+        // 每个空闲区间中第一个满足对齐要求的位置
+        let start = self.free_runs().find_map(|(start, end)| {
+            let aligned = (start + offset + align - 1) / align * align - offset;
+            if aligned + count <= end {
+                Some(aligned)
+            } else {
+                None
+            }
+        })?;
+        for index in start..start + count {
+            self.bits[index / 64] |= 1 << (index % 64);
+        }
+        Some(start)
+    }
+
+    fn largest_free_run(&self) -> usize {
+        self.free_runs()
+            .map(|(start, end)| end - start)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn free_ranges(&self) -> Vec<(usize, usize)> {
+        self.free_runs().collect()
+    }
+}
+
+impl BitmapAllocator {
+    /// 元素是否已被分配
+    fn is_allocated(&self, index: usize) -> bool {
+        self.bits[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// 按顺序迭代所有极大的空闲区间 `[start, end)`
+    fn free_runs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let total = self.bits.len() * 64;
+        let mut index = 0;
+        core::iter::from_fn(move || {
+            // 跳过已分配的部分，整字已满时直接跳过整个字
+            while index < total {
+                if index % 64 == 0 && self.bits[index / 64] == !0 {
+                    index += 64;
+                } else if self.is_allocated(index) {
+                    index += 1;
+                } else {
+                    break;
+                }
+            }
+            if index >= total {
+                return None;
+            }
+            let start = index;
+            // 向后延伸空闲区间，整字全空时直接跳过整个字
+            while index < total {
+                if index % 64 == 0 && self.bits[index / 64] == 0 {
+                    index += 64;
+                } else if !self.is_allocated(index) {
+                    index += 1;
+                } else {
+                    break;
+                }
+            }
+            Some((start, index))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn alloc_dealloc_round_trip() {
+        let mut allocator = BitmapAllocator::new(100);
+        let mut allocated: Vec<usize> = (0..100).map(|_| allocator.alloc().unwrap()).collect();
+        assert_eq!(allocator.alloc(), None);
+        allocated.sort_unstable();
+        assert_eq!(allocated, (0..100).collect::<Vec<_>>());
+        allocator.dealloc(42);
+        assert_eq!(allocator.alloc(), Some(42));
+        assert_eq!(allocator.alloc(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn dealloc_free_element() {
+        let mut allocator = BitmapAllocator::new(64);
+        allocator.dealloc(3);
+    }
+
+    #[test]
+    fn alloc_contiguous_alignment() {
+        let mut allocator = BitmapAllocator::new(256);
+        assert_eq!(allocator.alloc(), Some(0));
+        assert_eq!(allocator.alloc_contiguous(8, 16, 0), Some(16));
+        // 加上偏移之后对齐
+        let start = allocator.alloc_contiguous(4, 8, 3).unwrap();
+        assert_eq!((start + 3) % 8, 0);
+        assert!(start >= 1 && !(16..24).contains(&start));
+        for index in start..start + 4 {
+            assert!(allocator.is_allocated(index));
+        }
+        // 跨越字的边界
+        assert_eq!(allocator.alloc_contiguous(100, 64, 0), Some(64));
+    }
+
+    #[test]
+    fn alloc_contiguous_exhaustion() {
+        let mut allocator = BitmapAllocator::new(64);
+        assert_eq!(allocator.alloc_contiguous(65, 1, 0), None);
+        assert_eq!(allocator.alloc_contiguous(64, 1, 0), Some(0));
+        assert_eq!(allocator.alloc(), None);
+        assert_eq!(allocator.alloc_contiguous(1, 1, 0), None);
+        // 空闲的元素足够，但不连续
+        allocator.dealloc(10);
+        allocator.dealloc(20);
+        assert_eq!(allocator.alloc_contiguous(2, 1, 0), None);
+        assert_eq!(allocator.alloc_contiguous(1, 1, 0), Some(10));
+    }
+
+    #[test]
+    fn reserve() {
+        let mut allocator = BitmapAllocator::new(10);
+        assert!(allocator.reserve(5));
+        assert!(!allocator.reserve(5));
+        let allocated: Vec<usize> = (0..9).map(|_| allocator.alloc().unwrap()).collect();
+        assert!(!allocated.contains(&5));
+        assert_eq!(allocator.alloc(), None);
+        allocator.dealloc(5);
+        assert_eq!(allocator.alloc(), Some(5));
+    }
+
+    #[test]
+    fn free_runs() {
+        let mut allocator = BitmapAllocator::new(200);
+        assert_eq!(allocator.free_ranges(), vec![(0, 200)]);
+        assert_eq!(allocator.largest_free_run(), 200);
+        allocator.reserve(0);
+        allocator.reserve(70);
+        allocator.reserve(71);
+        allocator.reserve(199);
+        assert_eq!(allocator.free_ranges(), vec![(1, 70), (72, 199)]);
+        assert_eq!(allocator.largest_free_run(), 127);
+        allocator.dealloc(70);
+        assert_eq!(allocator.free_ranges(), vec![(1, 71), (72, 199)]);
+        assert_eq!(allocator.alloc_contiguous(199, 1, 0), None);
+        assert_eq!(allocator.alloc_contiguous(127, 1, 0), Some(72));
+        assert_eq!(allocator.free_ranges(), vec![(1, 71)]);
+        assert_eq!(allocator.largest_free_run(), 70);
+    }
+}
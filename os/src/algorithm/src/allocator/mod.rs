@@ -1,8 +1,11 @@
 //! 负责分配 / 回收的数据结构
 
+mod bitmap_allocator;
 mod segment_tree_allocator;
 mod stacked_allocator;
 
+use alloc::vec::Vec;
+
 /// 分配器：固定容量，每次分配 / 回收一个元素
 pub trait Allocator {
     /// 给定容量，创建分配器
@@ -11,10 +14,59 @@ pub trait Allocator {
     fn alloc(&mut self) -> Option<usize>;
     /// 回收一个元素
     fn dealloc(&mut self, index: usize);
+    /// 将给定的元素标记为已分配，使之后的分配不会返回它；元素已被分配则返回 `false`
+    ///
+    /// 默认的实现不断分配直到得到这个元素，再回收其余分配出的元素，
+    /// 能够直接修改内部状态的分配器应当覆盖它
+    fn reserve(&mut self, index: usize) -> bool {
+        let mut others = Vec::new();
+        let mut reserved = false;
+        while let Some(allocated) = self.alloc() {
+            if allocated == index {
+                reserved = true;
+                break;
+            }
+            others.push(allocated);
+        }
+        for other in others {
+            self.dealloc(other);
+        }
+        reserved
+    }
+}
+
+/// 能够分配连续多个元素的分配器
+pub trait ContiguousAllocator: Allocator {
+    /// 分配连续的 `count` 个元素，返回第一个元素，无法分配则返回 `None`
+    ///
+    /// 返回的下标加上 `offset` 之后是 `align` 的倍数，用于分配者的下标与实际地址之间有偏移的情况。
+    /// 分配出的元素仍然逐个通过 [`Allocator::dealloc`] 回收
+    fn alloc_contiguous(&mut self, count: usize, align: usize, offset: usize) -> Option<usize>;
+    /// 当前最长的连续空闲区间的长度
+    fn largest_free_run(&self) -> usize;
+    /// 所有极大的连续空闲区间 `[start, end)`，按下标递增排列
+    fn free_ranges(&self) -> Vec<(usize, usize)>;
 }
 
+pub use bitmap_allocator::BitmapAllocator;
 pub use segment_tree_allocator::SegmentTreeAllocator;
 pub use stacked_allocator::StackedAllocator;
 
 /// 默认使用的分配器
-pub type AllocatorImpl = StackedAllocator;
+pub type AllocatorImpl = BitmapAllocator;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_reserve() {
+        let mut allocator = StackedAllocator::new(8);
+        assert!(allocator.reserve(3));
+        assert!(!allocator.reserve(3));
+        let mut allocated: Vec<usize> = (0..7).map(|_| allocator.alloc().unwrap()).collect();
+        allocated.sort_unstable();
+        assert_eq!(allocated, [0, 1, 2, 4, 5, 6, 7]);
+        assert_eq!(allocator.alloc(), None);
+    }
+}
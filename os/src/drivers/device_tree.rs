@@ -3,9 +3,13 @@
 //! 递归遍历设备树并初始化
 
 use super::bus::virtio_mmio::virtio_probe;
-use crate::memory::VirtualAddress;
+use crate::memory::{
+    frame::FRAME_ALLOCATOR, set_memory_end, PhysicalAddress, Range, VirtualAddress,
+    KERNEL_MAP_OFFSET, MEMORY_END_ADDRESS, MEMORY_END_LIMIT,
+};
+use alloc::vec::Vec;
 use core::slice;
-use device_tree::{DeviceTree, Node};
+use device_tree::{util::SliceRead, DeviceTree, Node};
 
 /// 验证某内存段为设备树格式的 Magic Number（固定）
 const DEVICE_TREE_MAGIC: u32 = 0xd00d_feed;
@@ -24,6 +28,70 @@ fn walk(node: &Node) {
     }
 }
 
+/// 节点的 reg 属性描述的所有物理内存区间
+///
+/// 假定地址和大小都占两个 cell，即依次为 64 位的起始地址和大小
+fn reg_ranges(node: &Node) -> Vec<Range<PhysicalAddress>> {
+    let reg = match node.prop_raw("reg") {
+        Some(reg) => reg.as_slice(),
+        _ => return Vec::new(),
+    };
+    (0..reg.len() / 16)
+        .filter_map(|i| {
+            let start = reg.read_be_u64(i * 16).ok()? as usize;
+            let size = reg.read_be_u64(i * 16 + 8).ok()? as usize;
+            Some(Range::from(
+                PhysicalAddress(start)..PhysicalAddress(start + size),
+            ))
+        })
+        .collect()
+}
+
+/// 所有 `/memory` 节点描述的内存区间，按起始地址排序
+fn memory_banks(root: &Node) -> Vec<Range<PhysicalAddress>> {
+    let mut banks: Vec<Range<PhysicalAddress>> = root
+        .children
+        .iter()
+        .filter(|node| matches!(node.prop_str("device_type"), Ok("memory")))
+        .flat_map(reg_ranges)
+        .collect();
+    banks.sort_by_key(|bank| bank.start);
+    banks
+}
+
+/// 由设备树中的 `/memory` 节点得到可以访问的内存区域结束地址
+///
+/// 有多个内存区间时取覆盖它们的整个区间，区间之间的空洞由 [`init`] 从帧分配器中保留。
+/// 设备树中没有内存节点时返回 [`MEMORY_END_ADDRESS`]
+pub fn memory_end_from_dtb(dtb: &DeviceTree) -> PhysicalAddress {
+    memory_banks(&dtb.root)
+        .iter()
+        .map(|bank| bank.end)
+        .max()
+        .unwrap_or(MEMORY_END_ADDRESS)
+}
+
+/// 从帧分配器中保留不能分配的物理内存
+///
+/// 包括各个内存区间之间的空洞，以及 `/reserved-memory` 下各个节点描述的区域
+fn reserve_memory(root: &Node) {
+    let banks = memory_banks(root);
+    let holes = banks
+        .windows(2)
+        .map(|pair| Range::from(pair[0].end..pair[1].start));
+    let reserved = root
+        .children
+        .iter()
+        .filter(|node| node.name == "reserved-memory")
+        .flat_map(|node| node.children.iter())
+        .flat_map(reg_ranges);
+    for range in holes.chain(reserved) {
+        if let Err(message) = FRAME_ALLOCATOR.lock().reserve_range(range) {
+            println!("reserve {:x?}: {}", range, message);
+        }
+    }
+}
+
 /// 整个设备树的 Headers（用于验证和读取）
 struct DtbHeader {
     magic: u32,
@@ -31,7 +99,15 @@ struct DtbHeader {
 }
 
 /// 遍历设备树并初始化设备
-pub fn init(dtb_va: VirtualAddress) {
+///
+/// 这时还不知道内存的大小，[`memory_end`](crate::memory::memory_end) 仍是默认值，
+/// 而内存较大时设备树可能位于默认值之上，因此不经过带检查的线性映射转换，直接加上偏移访问。
+/// 启动页表的线性映射覆盖到 [`MEMORY_END_LIMIT`]，设备树不在此之下时无法访问，直接跳过
+pub fn init(dtb_pa: PhysicalAddress) {
+    if dtb_pa >= MEMORY_END_LIMIT {
+        return;
+    }
+    let dtb_va = VirtualAddress(dtb_pa.0 + KERNEL_MAP_OFFSET);
     let header = unsafe { &*(dtb_va.0 as *const DtbHeader) };
     // from_be 是大小端序的转换（from big endian）
     let magic = u32::from_be(header.magic);
@@ -39,7 +115,18 @@ pub fn init(dtb_va: VirtualAddress) {
         let size = u32::from_be(header.size);
         // 拷贝数据，加载并遍历
         let data = unsafe { slice::from_raw_parts(dtb_va.0 as *const u8, size as usize) };
-        if let Ok(dt) = DeviceTree::load(data) {
+        let dt = DeviceTree::load(data).ok();
+        // 帧分配器按内存区域的结束地址创建，必须在使用它之前设置
+        if let Some(dt) = &dt {
+            set_memory_end(memory_end_from_dtb(dt));
+        }
+        // 设备树本身所在的帧不能被分配出去
+        let range = Range::from(dtb_pa..dtb_pa + size as usize);
+        if let Err(message) = FRAME_ALLOCATOR.lock().reserve_range(range) {
+            println!("reserve device tree: {}", message);
+        }
+        if let Some(dt) = &dt {
+            reserve_memory(&dt.root);
             walk(&dt.root);
         }
     }
@@ -2,7 +2,7 @@
 //!
 //! 负责驱动管理
 
-use crate::memory::{PhysicalAddress, VirtualAddress};
+use crate::memory::PhysicalAddress;
 
 pub mod block;
 pub mod bus;
@@ -11,7 +11,6 @@ pub mod driver;
 
 /// 从设备树的物理地址来获取全部设备信息并初始化
 pub fn init(dtb_pa: PhysicalAddress) {
-    let dtb_va = VirtualAddress::from(dtb_pa);
-    device_tree::init(dtb_va);
+    device_tree::init(dtb_pa);
     println!("mod driver initialized")
 }
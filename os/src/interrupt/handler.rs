@@ -7,7 +7,9 @@ use crate::process::PROCESSOR;
 use crate::sbi::console_getchar;
 use riscv::register::{
     scause::{Exception, Interrupt, Scause, Trap},
-    sie, stvec,
+    sie,
+    sstatus::SPP,
+    stvec,
 };
 
 global_asm!(include_str!("../asm/interrupt.asm"));
@@ -53,6 +55,10 @@ pub fn handle_interrupt(context: &mut Context, scause: Scause, stval: usize) ->
         Trap::Interrupt(Interrupt::SupervisorTimer) => supervisor_timer(context),
         // 外部中断（键盘输入）
         Trap::Interrupt(Interrupt::SupervisorExternal) => supervisor_external(context),
+        // 缺页异常
+        Trap::Exception(Exception::LoadPageFault)
+        | Trap::Exception(Exception::StorePageFault)
+        | Trap::Exception(Exception::InstructionPageFault) => page_fault(context, scause, stval),
         // 其他情况，终止当前线程
         _ => fault(context, scause, stval),
     }
@@ -74,6 +80,33 @@ fn supervisor_timer(context: &mut Context) -> *mut Context {
     PROCESSOR.get().prepare_next_thread()
 }
 
+/// 处理缺页异常
+///
+/// 交给当前进程的 [`MemorySet::handle_page_fault`]，如果无法处理则终止线程
+fn page_fault(context: &mut Context, scause: Scause, stval: usize) -> *mut Context {
+    let access = match scause.cause() {
+        Trap::Exception(Exception::StorePageFault) => AccessType::Store,
+        Trap::Exception(Exception::InstructionPageFault) => AccessType::Fetch,
+        _ => AccessType::Load,
+    };
+    // 陷入之前的特权级
+    let from_user = matches!(context.sstatus.spp(), SPP::User);
+    let result = PROCESSOR
+        .get()
+        .current_thread()
+        .process
+        .write()
+        .memory_set
+        .handle_page_fault(VirtualAddress(stval), access, from_user);
+    match result {
+        Ok(()) => context,
+        Err(message) => {
+            println!("{}", message);
+            fault(context, scause, stval)
+        }
+    }
+}
+
 /// 出现未能解决的异常，终止当前线程
 fn fault(_context: &mut Context, scause: Scause, stval: usize) -> *mut Context {
     println!(
@@ -21,6 +21,18 @@ pub fn syscall_handler(context: &mut Context) -> *mut Context {
     // 无论如何处理，一定会跳过当前的 ecall 指令
     context.sepc += 4;
 
+    // 检查正在使用的页表属于当前线程所在的进程
+    if cfg!(debug_assertions) {
+        PROCESSOR
+            .get()
+            .current_thread()
+            .process
+            .read()
+            .memory_set
+            .mapping
+            .assert_active();
+    }
+
     let syscall_id = context.x[17];
     let args = [context.x[10], context.x[11], context.x[12]];
 
@@ -35,6 +35,10 @@
 //!   这允许我们在函数中间内联汇编使用 `ret` 提前结束，而不会导致栈出现异常
 #![feature(naked_functions)]
 #![feature(slice_fill)]
+//!
+//! - `#![feature(track_caller)]`
+//!   开启 `frame_trace` feature 时，用于记录每个物理帧是在哪里分配的
+#![cfg_attr(feature = "frame_trace", feature(track_caller))]
 
 #[macro_use]
 mod console;
@@ -48,8 +52,10 @@ mod process;
 mod sbi;
 
 use crate::memory::PhysicalAddress;
+use alloc::sync::Arc;
 use fs::*;
 use process::*;
+use spin::RwLock;
 use xmas_elf::ElfFile;
 
 extern crate alloc;
@@ -65,15 +71,26 @@ pub extern "C" fn rust_main(_hart_id: usize, dtb_pa: PhysicalAddress) -> ! {
     memory::init();
     interrupt::init();
     drivers::init(dtb_pa);
+    memory::FRAME_ALLOCATOR.lock().print_memory_map();
+    #[cfg(feature = "self_test")]
+    memory::self_test::run();
     fs::init();
 
-    start_user_thread("hello_world");
-    start_user_thread("notebook");
+    let processes = [
+        start_user_thread("hello_world"),
+        start_user_thread("notebook"),
+    ];
+
+    // 启动完成，去掉各个地址空间中内核 .text 和 .rodata 的写权限
+    for process in processes.iter() {
+        process.write().memory_set.lockdown();
+    }
 
     PROCESSOR.get().run()
 }
 
-fn start_user_thread(name: &str) {
+/// 创建运行给定程序的进程和线程，返回创建的进程
+fn start_user_thread(name: &str) -> Arc<RwLock<Process>> {
     // 从文件系统中找到程序
     let app = fs::ROOT_INODE.find(name).unwrap();
     // 读取数据
@@ -83,7 +100,8 @@ fn start_user_thread(name: &str) {
     // 利用 ELF 文件创建线程，映射空间并加载数据
     let process = Process::from_elf(&elf, true).unwrap();
     // 再从 ELF 中读出程序入口地址
-    let thread = Thread::new(process, elf.header.pt2.entry_point() as usize, None).unwrap();
+    let thread = Thread::new(process.clone(), elf.header.pt2.entry_point() as usize, None).unwrap();
     // 添加线程
     PROCESSOR.get().add_thread(thread);
+    process
 }
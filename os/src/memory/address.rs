@@ -2,7 +2,9 @@
 //!
 //! 我们为虚拟地址和物理地址分别设立两种类型，利用编译器检查来防止混淆。
 
-use super::config::{KERNEL_MAP_OFFSET, PAGE_SIZE};
+use super::config::{
+    memory_end, KERNEL_ADDRESS_MIN, KERNEL_MAP_OFFSET, PAGE_SIZE, USER_ADDRESS_MAX,
+};
 use bit_field::BitField;
 
 /// 虚拟地址
@@ -43,25 +45,62 @@ impl<T> From<*mut T> for VirtualAddress {
 /// 虚实页号之间的线性映射
 impl From<PhysicalPageNumber> for VirtualPageNumber {
     fn from(ppn: PhysicalPageNumber) -> Self {
-        Self(ppn.0 + KERNEL_MAP_OFFSET / PAGE_SIZE)
+        Self::from(ppn.linear_va())
     }
 }
 /// 虚实页号之间的线性映射
 impl From<VirtualPageNumber> for PhysicalPageNumber {
     fn from(vpn: VirtualPageNumber) -> Self {
-        Self(vpn.0 - KERNEL_MAP_OFFSET / PAGE_SIZE)
+        Self::from(VirtualAddress::from(vpn).linear_pa())
     }
 }
 /// 虚实地址之间的线性映射
 impl From<PhysicalAddress> for VirtualAddress {
     fn from(pa: PhysicalAddress) -> Self {
-        Self(pa.0 + KERNEL_MAP_OFFSET)
+        pa.linear_va()
     }
 }
 /// 虚实地址之间的线性映射
 impl From<VirtualAddress> for PhysicalAddress {
     fn from(va: VirtualAddress) -> Self {
-        Self(va.0 - KERNEL_MAP_OFFSET)
+        va.linear_pa()
+    }
+}
+
+// 线性映射：内核将物理地址 [0, memory_end()) 映射到加上 KERNEL_MAP_OFFSET 的虚拟地址。
+// 所有虚实转换都应通过下面的 `linear_va` / `linear_pa` 进行，debug 模式下会检查地址在这段区域内。
+// 区域的结束地址本身也允许转换，因为它常被用作区间的右端点。
+
+impl PhysicalAddress {
+    /// 经过线性映射得到内核可以访问的虚拟地址
+    pub fn linear_va(&self) -> VirtualAddress {
+        debug_assert!(
+            self.0 <= memory_end().0,
+            "physical address is out of the linear mapping"
+        );
+        VirtualAddress(self.0 + KERNEL_MAP_OFFSET)
+    }
+}
+impl VirtualAddress {
+    /// 由线性映射中的虚拟地址得到物理地址
+    pub fn linear_pa(&self) -> PhysicalAddress {
+        debug_assert!(
+            self.0 >= KERNEL_MAP_OFFSET && self.0 - KERNEL_MAP_OFFSET <= memory_end().0,
+            "virtual address is out of the linear mapping"
+        );
+        PhysicalAddress(self.0 - KERNEL_MAP_OFFSET)
+    }
+}
+impl PhysicalPageNumber {
+    /// 经过线性映射得到内核可以访问的虚拟地址
+    pub fn linear_va(&self) -> VirtualAddress {
+        PhysicalAddress::from(*self).linear_va()
+    }
+}
+impl VirtualPageNumber {
+    /// 由线性映射中的虚拟页号得到物理页号
+    pub fn linear_ppn(&self) -> PhysicalPageNumber {
+        PhysicalPageNumber::from(*self)
     }
 }
 impl VirtualAddress {
@@ -73,11 +112,21 @@ impl VirtualAddress {
     pub fn page_offset(&self) -> usize {
         self.0 % PAGE_SIZE
     }
+    /// 是否位于内核地址空间，即 [`KERNEL_ADDRESS_MIN`] 以上的高半部分
+    pub fn is_kernel(&self) -> bool {
+        *self >= KERNEL_ADDRESS_MIN
+    }
+    /// 是否位于用户地址空间，即 [`USER_ADDRESS_MAX`] 以下的低半部分
+    ///
+    /// 两半之间的地址不是合法的 Sv39 地址，既不属于内核也不属于用户
+    pub fn is_user(&self) -> bool {
+        *self < USER_ADDRESS_MAX
+    }
 }
 impl PhysicalAddress {
     /// 从物理地址经过线性映射取得 &mut 引用
     pub fn deref_kernel<T>(self) -> &'static mut T {
-        VirtualAddress::from(self).deref()
+        self.linear_va().deref()
     }
     /// 取得页内偏移
     pub fn page_offset(&self) -> usize {
@@ -93,18 +142,36 @@ impl VirtualPageNumber {
 impl PhysicalPageNumber {
     /// 从物理地址经过线性映射取得页面
     pub fn deref_kernel(self) -> &'static mut [u8; PAGE_SIZE] {
-        PhysicalAddress::from(self).deref_kernel()
+        self.linear_va().deref()
+    }
+}
+
+/// 虚拟页号在各级页表中的下标，从根页表开始，由 [`VirtualPageNumber::levels`] 得到
+#[derive(Copy, Clone, Debug)]
+pub struct PageLevels {
+    indices: [usize; 4],
+    count: usize,
+}
+
+impl core::ops::Deref for PageLevels {
+    type Target = [usize];
+    fn deref(&self) -> &Self::Target {
+        &self.indices[..self.count]
     }
 }
 
 impl VirtualPageNumber {
-    /// 得到一、二、三级页号
-    pub fn levels(self) -> [usize; 3] {
-        [
-            self.0.get_bits(18..27),
-            self.0.get_bits(9..18),
-            self.0.get_bits(0..9),
-        ]
+    /// 得到各级页表中的下标，从根页表开始
+    ///
+    /// `count` 为页表的级数：Sv39 为 3，Sv48 为 4
+    pub fn levels(self, count: usize) -> PageLevels {
+        assert!(count <= 4, "too many page table levels");
+        let mut indices = [0; 4];
+        for (level, index) in indices[..count].iter_mut().enumerate() {
+            let shift = 9 * (count - 1 - level);
+            *index = self.0.get_bits(shift..shift + 9);
+        }
+        PageLevels { indices, count }
     }
 }
 
@@ -135,6 +202,13 @@ macro_rules! implement_address_to_page_number {
             pub const fn ceil(address: $address_type) -> Self {
                 Self(address.0 / PAGE_SIZE + (address.0 % PAGE_SIZE != 0) as usize)
             }
+            /// 从这个页号开始的 `count` 个连续页号，不需要先构造 [`Range`](crate::memory::Range)
+            ///
+            /// 最后一个页号溢出时直接 panic，而不是回绕
+            pub fn iter_count(self, count: usize) -> impl Iterator<Item = Self> {
+                let end = self.0.checked_add(count).expect("page number overflow");
+                (self.0..end).map(Self)
+            }
         }
     };
 }
@@ -209,3 +283,43 @@ implement_usize_operations! {PhysicalAddress}
 implement_usize_operations! {VirtualAddress}
 implement_usize_operations! {PhysicalPageNumber}
 implement_usize_operations! {VirtualPageNumber}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_split_page_number() {
+        let vpn = VirtualPageNumber((5 << 18) | (3 << 9) | 7);
+        assert_eq!(&*vpn.levels(3), &[5, 3, 7]);
+        // Sv48 多一级，最高的 9 位是根页表中的下标
+        let vpn = VirtualPageNumber((1 << 27) | vpn.0);
+        assert_eq!(&*vpn.levels(4), &[1, 5, 3, 7]);
+        assert_eq!(&*vpn.levels(3), &[5, 3, 7]);
+    }
+
+    #[test]
+    fn floor_and_ceil() {
+        assert_eq!(
+            VirtualPageNumber::floor(VirtualAddress(0x1fff)),
+            VirtualPageNumber(1)
+        );
+        assert_eq!(
+            VirtualPageNumber::ceil(VirtualAddress(0x1001)),
+            VirtualPageNumber(2)
+        );
+        assert_eq!(
+            VirtualPageNumber::ceil(VirtualAddress(0x1000)),
+            VirtualPageNumber(1)
+        );
+    }
+
+    #[test]
+    fn address_halves() {
+        assert!(VirtualAddress(0x1000).is_user());
+        assert!(KERNEL_ADDRESS_MIN.is_kernel());
+        // 两半之间的地址既不属于用户也不属于内核
+        let hole = VirtualAddress(USER_ADDRESS_MAX.0);
+        assert!(!hole.is_user() && !hole.is_kernel());
+    }
+}
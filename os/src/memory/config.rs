@@ -1,6 +1,7 @@
 //! 定义一些内存相关的常量
 
 use super::address::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::*;
 
 /// 页 / 帧大小，必须是 2^n
@@ -11,10 +12,49 @@ pub const DEVICE_START_ADDRESS: PhysicalAddress = PhysicalAddress(0x1000_0000);
 /// MMIO 设备段内存区域结束地址
 pub const DEVICE_END_ADDRESS: PhysicalAddress = PhysicalAddress(0x1001_0000);
 
+/// CLINT（核心本地中断器）的 MMIO 区域起始地址
+pub const CLINT_START_ADDRESS: PhysicalAddress = PhysicalAddress(0x0200_0000);
+/// CLINT 的 MMIO 区域结束地址
+pub const CLINT_END_ADDRESS: PhysicalAddress = PhysicalAddress(0x0201_0000);
+
+/// PLIC（平台级中断控制器）的 MMIO 区域起始地址
+pub const PLIC_START_ADDRESS: PhysicalAddress = PhysicalAddress(0x0c00_0000);
+/// PLIC 的 MMIO 区域结束地址，包括优先级、使能，以及前 512 个上下文的阈值和 claim 寄存器
+pub const PLIC_END_ADDRESS: PhysicalAddress = PhysicalAddress(0x0c40_0000);
+
 /// 可以访问的内存区域起始地址
 pub const MEMORY_START_ADDRESS: PhysicalAddress = PhysicalAddress(0x8000_0000);
-/// 可以访问的内存区域结束地址
+/// 可以访问的内存区域结束地址的默认值，设备树没有给出内存大小时使用
+///
+/// 实际的结束地址见 [`memory_end`]
 pub const MEMORY_END_ADDRESS: PhysicalAddress = PhysicalAddress(0x8800_0000);
+/// 可以访问的内存区域结束地址的上限
+///
+/// 启动页表（`entry.asm`）的线性映射只覆盖到这里，更高的内存在切换到内核页表之前无法访问
+pub const MEMORY_END_LIMIT: PhysicalAddress = PhysicalAddress(0xc000_0000);
+
+/// 由设备树得到的内存区域结束地址，为 0 表示尚未设置
+static MEMORY_END: AtomicUsize = AtomicUsize::new(0);
+
+/// 可以访问的内存区域结束地址
+///
+/// 由 [`set_memory_end`] 按设备树设置，在此之前为 [`MEMORY_END_ADDRESS`]
+pub fn memory_end() -> PhysicalAddress {
+    match MEMORY_END.load(Ordering::Relaxed) {
+        0 => MEMORY_END_ADDRESS,
+        end => PhysicalAddress(end),
+    }
+}
+
+/// 设置可以访问的内存区域结束地址，超过 [`MEMORY_END_LIMIT`] 的部分被截去
+///
+/// 必须在第一次使用 [`FRAME_ALLOCATOR`] 之前调用，帧分配器在创建时按这个地址确定可用区间；
+/// 内核的线性映射也按这个地址建立。
+///
+/// [`FRAME_ALLOCATOR`]: static@crate::memory::FRAME_ALLOCATOR
+pub fn set_memory_end(end: PhysicalAddress) {
+    MEMORY_END.store(end.0.min(MEMORY_END_LIMIT.0), Ordering::Relaxed);
+}
 
 lazy_static! {
     /// 内核代码结束的地址，即可以用来分配的内存起始地址
@@ -25,6 +65,39 @@ lazy_static! {
 /// 操作系统动态分配内存所用的堆大小（8M）
 pub const KERNEL_HEAP_SIZE: usize = 0x80_0000;
 
+/// 用户地址空间的上界（不含），即 Sv39 中第 38 位为 0 的低半部分
+///
+/// 带有 USER 位的字段必须完全位于这个地址以下
+pub const USER_ADDRESS_MAX: VirtualAddress = VirtualAddress(0x40_0000_0000);
+/// 内核地址空间的下界，即 Sv39 中第 38 位为 1 的高半部分
+///
+/// 不带 USER 位的字段必须完全位于这个地址以上
+pub const KERNEL_ADDRESS_MIN: VirtualAddress = VirtualAddress(0xffff_ffc0_0000_0000);
+
+/// 用户进程栈顶的虚拟地址，栈从这里向下增长
+pub const USER_STACK_TOP: VirtualAddress = VirtualAddress(0x8000_0000);
+/// 用户进程 mmap 区域的起始虚拟地址，在这之上寻找空闲区间
+pub const USER_MMAP_BASE: VirtualAddress = VirtualAddress(0x4000_0000);
+
+/// 内核动态映射区域的起始地址，[`MemorySet::alloc_anywhere`] 在这个区域中寻找空闲区间
+///
+/// 位于内核地址空间的下界之上、线性映射之下，两者不会重叠
+///
+/// [`MemorySet::alloc_anywhere`]: crate::memory::mapping::MemorySet::alloc_anywhere
+pub const KERNEL_VMALLOC_BASE: VirtualAddress = VirtualAddress(0xffff_ffd0_0000_0000);
+/// 内核动态映射区域的结束地址（不含）
+pub const KERNEL_VMALLOC_END: VirtualAddress = VirtualAddress(0xffff_ffe0_0000_0000);
+
+/// 陷入暂存页的起始地址，第 `hart` 个核的暂存页是这之上的第 `hart` 页
+///
+/// 位于动态映射区域之上、线性映射之下，在每个地址空间中都相同，
+/// 见 [`MemorySet::map_trap_scratch`]
+///
+/// [`MemorySet::map_trap_scratch`]: crate::memory::mapping::MemorySet::map_trap_scratch
+pub const TRAP_SCRATCH_BASE: VirtualAddress = VirtualAddress(0xffff_ffe0_0000_0000);
+/// 陷入暂存页最多支持的核数
+pub const TRAP_SCRATCH_HARTS: usize = 64;
+
 /// 内核使用线性映射的偏移量
 pub const KERNEL_MAP_OFFSET: usize = 0xffff_ffff_0000_0000;
 
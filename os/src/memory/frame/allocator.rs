@@ -5,23 +5,41 @@
 use super::*;
 use crate::memory::*;
 use algorithm::*;
+#[cfg(feature = "frame_trace")]
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+#[cfg(feature = "frame_trace")]
+use core::panic::Location;
 use lazy_static::*;
 use spin::Mutex;
 
+/// 开启 `frame_poison` feature 时，debug 模式下被回收的帧会用这个字节填满
+#[cfg(all(feature = "frame_poison", debug_assertions))]
+const FRAME_POISON: u8 = 0xde;
+
 lazy_static! {
     /// 帧分配器
     pub static ref FRAME_ALLOCATOR: Mutex<FrameAllocator<AllocatorImpl>> = Mutex::new(FrameAllocator::new(Range::from(
-            PhysicalPageNumber::ceil(PhysicalAddress::from(*KERNEL_END_ADDRESS))..PhysicalPageNumber::floor(MEMORY_END_ADDRESS),
+            PhysicalPageNumber::ceil(PhysicalAddress::from(*KERNEL_END_ADDRESS))..PhysicalPageNumber::floor(memory_end()),
         )
     ));
 }
 
-/// 基于线段树的帧分配 / 回收
+/// 帧分配 / 回收，具体的数据结构由 [`AllocatorImpl`] 决定
 pub struct FrameAllocator<T: Allocator> {
     /// 可用区间的起始
     start_ppn: PhysicalPageNumber,
+    /// 可用区间的结束
+    end_ppn: PhysicalPageNumber,
     /// 分配器
     allocator: T,
+    /// 剩余可分配的帧数
+    free_count: usize,
+    /// 通过 [`FrameAllocator::reserve_range`] 保留的区间
+    reserved: Vec<Range<PhysicalPageNumber>>,
+    /// 每个尚未释放的帧是在哪里分配的
+    #[cfg(feature = "frame_trace")]
+    traces: BTreeMap<PhysicalPageNumber, &'static Location<'static>>,
 }
 
 impl<T: Allocator> FrameAllocator<T> {
@@ -29,22 +47,177 @@ impl<T: Allocator> FrameAllocator<T> {
     pub fn new(range: impl Into<Range<PhysicalPageNumber>> + Copy) -> Self {
         FrameAllocator {
             start_ppn: range.into().start,
+            end_ppn: range.into().end,
             allocator: T::new(range.into().len()),
+            free_count: range.into().len(),
+            reserved: Vec::new(),
+            #[cfg(feature = "frame_trace")]
+            traces: BTreeMap::new(),
         }
     }
 
     /// 分配帧，如果没有剩余则返回 `Err`
+    ///
+    /// 开启 `frame_trace` feature 时，会记录调用者的位置
+    #[cfg_attr(feature = "frame_trace", track_caller)]
     pub fn alloc(&mut self) -> MemoryResult<FrameTracker> {
-        self.allocator
+        let frame = self
+            .allocator
             .alloc()
             .ok_or("no available frame to allocate")
-            .map(|offset| FrameTracker(self.start_ppn + offset))
+            .map(|offset| FrameTracker(self.start_ppn + offset))?;
+        self.free_count -= 1;
+        #[cfg(feature = "frame_trace")]
+        self.traces.insert(frame.page_number(), Location::caller());
+        Ok(frame)
+    }
+
+    /// 分配帧并将其内容清零，如果没有剩余则返回 `Err`
+    ///
+    /// 通过线性映射访问帧的物理地址来清零。映射新页面、新建页表时都应使用这个函数，
+    /// 而不是在分配之后各自清零。
+    #[cfg_attr(feature = "frame_trace", track_caller)]
+    pub fn alloc_zeroed(&mut self) -> MemoryResult<FrameTracker> {
+        let mut frame = self.alloc()?;
+        frame.fill(0);
+        Ok(frame)
     }
 
     /// 将被释放的帧添加到空闲列表的尾部
     ///
-    /// 这个函数会在 [`FrameTracker`] 被 drop 时自动调用，不应在其他地方调用
+    /// 这个函数会在 [`FrameTracker`] 被 drop 时自动调用，不应在其他地方调用。
+    /// 开启 `frame_poison` feature 时，debug 模式下会先将帧用 `0xde` 填满，
+    /// 释放后仍通过物理地址读取它的代码会读到明显的垃圾数据。
+    /// [`alloc_zeroed`](FrameAllocator::alloc_zeroed) 会在重新使用前将其清除，
+    /// 而 [`alloc`](FrameAllocator::alloc) 得到的帧保持这个值，同样便于发现未初始化的读取
     pub(super) fn dealloc(&mut self, frame: &FrameTracker) {
+        #[cfg(all(feature = "frame_poison", debug_assertions))]
+        frame.page_number().deref_kernel().fill(FRAME_POISON);
+        #[cfg(feature = "frame_trace")]
+        self.traces.remove(&frame.page_number());
         self.allocator.dealloc(frame.page_number() - self.start_ppn);
+        self.free_count += 1;
+    }
+
+    /// 将一段物理地址所在的帧标记为已占用，之后的分配不会再返回它们
+    ///
+    /// 用于设备树、固件保留区域等不能交给分配器的内存。区间首尾不对齐的帧也会被保留，
+    /// 不在可用区间中的部分直接忽略。被保留的帧不会再被回收。
+    /// 如果其中某个帧已经被分配出去则返回 `Err`，此时其余的帧仍然会被保留
+    pub fn reserve_range(&mut self, phys_range: Range<PhysicalAddress>) -> MemoryResult<()> {
+        let start = PhysicalPageNumber::floor(phys_range.start).max(self.start_ppn);
+        let end = PhysicalPageNumber::ceil(phys_range.end).min(self.end_ppn);
+        if start < end {
+            self.reserved.push(Range::from(start..end));
+        }
+        let mut result = Ok(());
+        for ppn in start.0..end.0 {
+            if self.allocator.reserve(ppn - self.start_ppn.0) {
+                self.free_count -= 1;
+            } else {
+                result = Err("reserved frame is already allocated");
+            }
+        }
+        result
+    }
+
+    /// 剩余可分配的帧数
+    pub fn free_count(&self) -> usize {
+        self.free_count
+    }
+
+    /// 打印所有尚未释放的帧及其分配位置
+    #[cfg(feature = "frame_trace")]
+    pub fn dump_outstanding(&self) {
+        println!("{} frames outstanding", self.traces.len());
+        for (ppn, location) in self.traces.iter() {
+            println!("{} allocated at {}", ppn, location);
+        }
+    }
+}
+
+impl<T: ContiguousAllocator> FrameAllocator<T> {
+    /// 分配物理上连续的 `count` 个帧，第一个帧的物理页号是 `align` 的倍数，
+    /// 如果没有足够长的空闲区间则返回 `Err`
+    ///
+    /// 返回的帧按物理页号递增排列，内容没有清零，每个帧仍然在 drop 时各自回收。
+    /// 开启 `frame_trace` feature 时，会记录调用者的位置
+    #[cfg_attr(feature = "frame_trace", track_caller)]
+    pub fn alloc_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> MemoryResult<Vec<FrameTracker>> {
+        let start = self
+            .allocator
+            .alloc_contiguous(count, align, self.start_ppn.0 % align)
+            .ok_or("no contiguous frames to allocate")?;
+        self.free_count -= count;
+        let frames: Vec<FrameTracker> = (self.start_ppn + start)
+            .iter_count(count)
+            .map(FrameTracker)
+            .collect();
+        #[cfg(feature = "frame_trace")]
+        for frame in frames.iter() {
+            self.traces.insert(frame.page_number(), Location::caller());
+        }
+        Ok(frames)
+    }
+
+    /// 当前最多能连续分配多少个帧
+    pub fn largest_free_run(&self) -> usize {
+        self.allocator.largest_free_run()
+    }
+
+    /// 所有连续的空闲区间，按物理页号递增排列，相邻的空闲帧合并为一个区间
+    pub fn free_regions(&self) -> Vec<Range<PhysicalPageNumber>> {
+        self.allocator
+            .free_ranges()
+            .into_iter()
+            .map(|(start, end)| Range::from(self.start_ppn + start..self.start_ppn + end))
+            .collect()
+    }
+
+    /// 打印物理内存的布局：整个内存、内核、保留的区间和空闲的区间，例如
+    ///
+    /// ```text
+    /// RAM:      0x80000000-0x88000000
+    /// kernel:   0x80200000-0x80a34000
+    /// reserved: 0x87e00000-0x87e02000
+    /// free:     0x80a34000-0x87e00000 (29132 frames)
+    /// ```
+    ///
+    /// 既不属于内核、保留区间，也不空闲的帧都已经被分配出去
+    pub fn print_memory_map(&self) {
+        // 在 linker.ld 里面标记的内核代码的起始点
+        extern "C" {
+            fn text_start();
+        }
+        let address = |ppn: PhysicalPageNumber| PhysicalAddress::from(ppn).0;
+        println!(
+            "RAM:      {:#x}-{:#x}",
+            MEMORY_START_ADDRESS.0,
+            memory_end().0
+        );
+        println!(
+            "kernel:   {:#x}-{:#x}",
+            PhysicalAddress::from(VirtualAddress(text_start as usize)).0,
+            address(self.start_ppn)
+        );
+        for range in self.reserved.iter() {
+            println!(
+                "reserved: {:#x}-{:#x}",
+                address(range.start),
+                address(range.end)
+            );
+        }
+        for range in self.free_regions() {
+            println!(
+                "free:     {:#x}-{:#x} ({} frames)",
+                address(range.start),
+                address(range.end),
+                range.len()
+            );
+        }
     }
 }
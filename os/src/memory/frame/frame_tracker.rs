@@ -30,6 +30,14 @@ impl FrameTracker {
     pub fn page_number(&self) -> PhysicalPageNumber {
         self.0
     }
+    /// 通过线性映射将整个帧的内容复制到 `dst` 中
+    ///
+    /// 写时复制、复制地址空间和迁移页面都通过它复制帧。两个帧必须不同，
+    /// 否则同一块内存会同时被 `&` 和 `&mut` 引用
+    pub fn clone_contents_into(&self, dst: &mut FrameTracker) {
+        assert_ne!(self.0, dst.0, "copying a frame into itself");
+        dst.copy_from_slice(&self[..]);
+    }
 }
 
 /// `FrameTracker` 可以 deref 得到对应的 `[u8; PAGE_SIZE]`
@@ -0,0 +1,185 @@
+//! 带有堆、栈和 mmap 区域布局的地址空间 [`AddressSpace`]
+//!
+//! [`MemorySet`] 只负责按 [`Segment`] 建立映射，而不关心它们放在哪里。
+//! `AddressSpace` 在其上记录堆顶、栈底和 mmap 区域，决定新的映射应当放在什么位置。
+//!
+//! # 布局
+//! ```text
+//! USER_STACK_TOP  +-----------+
+//!                 |   stack   |  向下增长
+//!                 +-----------+  <- stack_bottom
+//!                 |           |
+//!                 |   mmap    |  从 USER_MMAP_BASE 向上寻找空闲区间
+//! USER_MMAP_BASE  +-----------+
+//!                 |           |
+//!                 +-----------+  <- heap_end（brk）
+//!                 |   heap    |  向上增长
+//!                 +-----------+  <- heap_start
+//!                 | elf 各字段 |
+//!                 +-----------+
+//! ```
+
+use crate::memory::{
+    address::*,
+    config::*,
+    mapping::{Flags, MapType, MemorySet, Segment},
+    range::Range,
+    MemoryResult,
+};
+use alloc::vec::Vec;
+use xmas_elf::ElfFile;
+
+/// 堆和栈相遇时返回的错误
+///
+/// 在添加字段之前就检查，而不是等到映射时才发现页面已经映射。调用者可以与它比较，区分其他映射错误
+pub const HEAP_STACK_COLLISION: &str = "heap and stack would collide";
+
+/// 一个进程的地址空间
+pub struct AddressSpace {
+    /// 实际的映射和所有字段
+    pub memory_set: MemorySet,
+    /// 是否属于用户态，决定新映射是否带有 USER 位
+    is_user: bool,
+    /// 堆的起始地址（页对齐）
+    heap_start: VirtualAddress,
+    /// 当前的堆顶，即 brk
+    heap_end: VirtualAddress,
+    /// 堆已经映射到的位置（页对齐），可能高于 `heap_end`
+    heap_mapped_end: VirtualAddress,
+    /// 堆增长时添加的各个字段，按地址递增排列
+    heap_segments: Vec<Segment>,
+    /// 栈已经映射到的最低地址
+    stack_bottom: VirtualAddress,
+    /// mmap 区域的起始地址
+    mmap_base: VirtualAddress,
+}
+
+impl AddressSpace {
+    /// 在已有的 [`MemorySet`] 上建立地址空间，堆从 `heap_start` 开始
+    pub fn new(memory_set: MemorySet, heap_start: VirtualAddress, is_user: bool) -> Self {
+        let heap_start = VirtualAddress::from(VirtualPageNumber::ceil(heap_start));
+        Self {
+            memory_set,
+            is_user,
+            heap_start,
+            heap_end: heap_start,
+            heap_mapped_end: heap_start,
+            heap_segments: Vec::new(),
+            stack_bottom: USER_STACK_TOP,
+            mmap_base: USER_MMAP_BASE,
+        }
+    }
+
+    /// 通过 elf 文件创建地址空间，堆紧接在 elf 最高的字段之后
+    pub fn from_elf(file: &ElfFile, is_user: bool) -> MemoryResult<Self> {
+        let memory_set = MemorySet::from_elf(file, is_user)?;
+        // elf 中的字段都是按帧映射的，内核的字段则都是线性映射
+        let heap_start = memory_set
+            .segments
+            .iter()
+            .filter(|segment| segment.map_type == MapType::Framed)
+            .map(|segment| segment.range.end)
+            .max()
+            .unwrap_or_default();
+        Ok(Self::new(memory_set, heap_start, is_user))
+    }
+
+    /// 当前的堆顶
+    pub fn heap_end(&self) -> VirtualAddress {
+        self.heap_end
+    }
+
+    /// 栈顶地址
+    pub fn stack_top(&self) -> VirtualAddress {
+        USER_STACK_TOP
+    }
+
+    /// 调整堆顶，返回新的堆顶
+    ///
+    /// 增长时分配并映射新的页面；缩小时只回收完全位于新堆顶之上的字段，
+    /// 残留的部分页面会在下次增长时继续使用。
+    /// 堆不能越过 mmap 区域的起始，也不能与向下增长的栈相遇，否则返回 `Err` 而不做任何映射。
+    pub fn brk(&mut self, new_brk: VirtualAddress) -> MemoryResult<VirtualAddress> {
+        if new_brk < self.heap_start {
+            return Err("brk below the start of heap");
+        }
+        let new_mapped_end = VirtualAddress::from(VirtualPageNumber::ceil(new_brk));
+        if new_mapped_end > self.heap_mapped_end {
+            let segment =
+                self.new_segment(Range::from(self.heap_mapped_end..new_mapped_end), Flags::RW);
+            if segment.range.end > self.stack_bottom {
+                return Err(HEAP_STACK_COLLISION);
+            }
+            if segment.range.end > self.mmap_base {
+                return Err("heap overlaps with mmap area");
+            }
+            self.memory_set.add_segment(segment.clone(), None)?;
+            self.heap_segments.push(segment);
+            self.heap_mapped_end = new_mapped_end;
+        } else {
+            while let Some(segment) = self.heap_segments.last() {
+                if segment.range.start < new_mapped_end {
+                    break;
+                }
+                let segment = self.heap_segments.pop().unwrap();
+                self.memory_set.remove_segment(&segment)?;
+                self.heap_mapped_end = segment.range.start;
+            }
+        }
+        self.heap_end = new_brk;
+        Ok(new_brk)
+    }
+
+    /// 在 mmap 区域中找到一段长度为 `len` 的空闲空间并映射，返回对应的地址区间
+    ///
+    /// `flags` 只需包括 rwx 权限，user 位会根据地址空间而定。
+    pub fn mmap(&mut self, len: usize, flags: Flags) -> MemoryResult<Range<VirtualAddress>> {
+        // 只能按页分配，所以让 len 向上取整页
+        let alloc_size = (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let mut range = Range::<VirtualAddress>::from(self.mmap_base..self.mmap_base + alloc_size);
+        while self.memory_set.overlap_with(range.into()) {
+            range.start += alloc_size;
+            range.end += alloc_size;
+        }
+        if range.end > self.stack_bottom {
+            return Err("no free space for mmap");
+        }
+        self.memory_set
+            .add_segment(self.new_segment(range, flags), None)?;
+        // 返回地址区间（使用参数 len，而非向上取整的 alloc_size）
+        Ok(Range::from(range.start..(range.start + len)))
+    }
+
+    /// 将栈向下扩展 `pages` 个页面，返回新映射的地址区间
+    ///
+    /// 栈不能与向上增长的堆相遇，否则返回 `Err` 而不做任何映射
+    pub fn map_stack(&mut self, pages: usize) -> MemoryResult<Range<VirtualAddress>> {
+        let start = pages
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| self.stack_bottom.0.checked_sub(size))
+            .map(VirtualAddress)
+            .ok_or(HEAP_STACK_COLLISION)?;
+        if start < self.heap_mapped_end {
+            return Err(HEAP_STACK_COLLISION);
+        }
+        let range = Range::from(start..self.stack_bottom);
+        self.memory_set
+            .add_segment(self.new_segment(range, Flags::RW), None)?;
+        self.stack_bottom = range.start;
+        Ok(range)
+    }
+
+    /// 激活地址空间对应的页表
+    pub fn activate(&self) {
+        self.memory_set.activate();
+    }
+
+    /// 创建一个按帧映射的字段，根据地址空间加上 user 位
+    fn new_segment(&self, range: Range<VirtualAddress>, flags: Flags) -> Segment {
+        Segment {
+            map_type: MapType::Framed,
+            range,
+            flags: flags | Flags::user(self.is_user),
+        }
+    }
+}
@@ -1,51 +1,580 @@
-//! Rv39 页表的构建 [`Mapping`]
+//! Sv39 / Sv48 页表的构建 [`Mapping`]
+//!
+//! 每个映射在创建时确定分页模式（见 [`Mapping::with_mode`]），默认使用三级页表的 Sv39，
+//! 硬件支持时也可以使用四级页表的 Sv48。查找时按照模式的级数逐级进行。
 //!
 //! 许多方法返回 [`Result`]，如果出现错误会返回 `Err(message)`。设计目标是，此时如果终止线程，则不会产生后续问题。
 //! 但是如果错误是由操作系统代码逻辑产生的，则会直接 panic。
+//!
+//! 中间页表在映射时按需创建：[`Mapping::find_entry`] 和 [`Mapping::map_one`] 遇到缺失的页表会分配新的页表，
+//! 按需映射的字段在缺页之前不会创建任何页表。只读的查找（[`Mapping::translate`]、[`Mapping::lookup`]、
+//! [`Mapping::is_mapped`]）遇到缺失的页表则返回 `None` / `false`，不会分配。
+//! 页表本身总是常驻内存，不支持将页表换出，因此不存在“页表所在的页面被换出”导致的缺页。
 
 use crate::memory::{
     address::*,
-    config::PAGE_SIZE,
+    config::{KERNEL_ADDRESS_MIN, PAGE_SIZE, USER_ADDRESS_MAX},
     frame::{FrameTracker, FRAME_ALLOCATOR},
-    mapping::{Flags, MapType, PageTable, PageTableEntry, PageTableTracker, Segment},
+    mapping::{
+        Flags, MapType, OomAction, OomHandler, PageTable, PageTableEntry, PageTableTracker,
+        PageTableWalker, Segment, TlbShootdown, OOM_KILLED,
+    },
+    range::Range,
     MemoryResult,
 };
-use alloc::{vec, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
 use core::cmp::min;
+use core::fmt::{self, Write};
+use core::ops::{Deref, DerefMut};
 use core::ptr::slice_from_raw_parts_mut;
+use lazy_static::*;
+
+/// 一个批次中推迟刷新的页面超过这个数量时，结束时直接刷新整个 TLB
+const BATCH_FLUSH_ALL_THRESHOLD: usize = 64;
+
+/// `satp` 中的页号部分（低 44 位）
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+/// `satp` 高 4 位表示的分页模式
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum SatpMode {
+    /// 不进行地址转换
+    Bare = 0,
+    /// 三级页表，39 位虚拟地址
+    Sv39 = 8,
+    /// 四级页表，48 位虚拟地址
+    Sv48 = 9,
+}
+
+impl SatpMode {
+    /// 写入 `satp` 高 4 位的值
+    pub fn bits(self) -> usize {
+        self as usize
+    }
+
+    /// 页表的级数，Bare 模式不使用页表
+    pub fn levels(self) -> usize {
+        match self {
+            SatpMode::Bare => 0,
+            SatpMode::Sv39 => 3,
+            SatpMode::Sv48 => 4,
+        }
+    }
+}
+
+impl Default for SatpMode {
+    fn default() -> Self {
+        SatpMode::Sv39
+    }
+}
+
+lazy_static! {
+    /// 硬件支持的最高分页模式，第一次调用 [`Mapping::paging_mode`] 时探测
+    static ref PROBED_SATP_MODE: SatpMode = Mapping::probe_satp_mode();
+}
 
 #[derive(Default)]
 /// 某个进程的内存映射关系
 pub struct Mapping {
     /// 保存所有使用到的页表
-    page_tables: Vec<PageTableTracker>,
+    ///
+    /// 页表通过 `Arc` 保存，以便通过 [`Mapping::link_kernel_subtree`] 与其他映射共用：
+    /// 共用的页表只有在所有引用它的映射都被 drop 之后才会释放
+    page_tables: Vec<Arc<PageTableTracker>>,
+    /// 根页表中与内核映射共用的页表项下标，见 [`Mapping::link_kernel_subtree`]
+    kernel_slots: Vec<usize>,
     /// 根页表的物理页号
     root_ppn: PhysicalPageNumber,
+    /// 分页模式，决定页表的级数
+    mode: SatpMode,
+    /// 修改页表项后通知其他核刷新 TLB，单核时为 `None`，不做任何事
+    shootdown: Option<Arc<dyn TlbShootdown>>,
+    /// 除本核以外，当前映射还在哪些核上生效
+    remote_harts: Vec<usize>,
+    /// 分配物理帧失败时的处理方式，为 `None` 时直接返回错误
+    oom_handler: Option<Arc<dyn OomHandler>>,
+    /// 处于 [`FlushBatch`] 中时，记录推迟刷新的页面
+    batch: Option<Vec<VirtualPageNumber>>,
+    /// 每当已有的翻译可能改变时增加，见 [`Mapping::generation`]
+    generation: u64,
+    /// 新建字段时自动加上的标志位，见 [`Mapping::set_default_flags`]
+    default_flags: Flags,
+}
+
+/// 批量修改映射时推迟并合并 TLB 刷新，由 [`Mapping::begin_batch`] 创建
+///
+/// 通过 `Deref` 使用其中的 [`Mapping`]。drop 时，如果推迟的页面不多，则逐个刷新，否则刷新整个 TLB。
+pub struct FlushBatch<'a> {
+    mapping: &'a mut Mapping,
+    /// 只有最外层的批次在结束时刷新，嵌套的批次什么也不做
+    outermost: bool,
+}
+
+impl Deref for FlushBatch<'_> {
+    type Target = Mapping;
+    fn deref(&self) -> &Self::Target {
+        self.mapping
+    }
+}
+
+impl DerefMut for FlushBatch<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.mapping
+    }
+}
+
+impl Drop for FlushBatch<'_> {
+    fn drop(&mut self) {
+        if !self.outermost {
+            return;
+        }
+        let pages = self.mapping.batch.take().unwrap();
+        if pages.len() > BATCH_FLUSH_ALL_THRESHOLD {
+            self.mapping.flush_all();
+        } else {
+            for vpn in pages {
+                self.mapping.flush(vpn);
+            }
+        }
+    }
+}
+
+/// 映射中所有叶子页表项的标志位，由 [`Mapping::snapshot_flags`] 创建
+///
+/// 用于临时修改权限之后恢复，例如调试器通过去掉 `EXECUTABLE` 设置断点
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FlagsSnapshot {
+    /// 每个叶子页表项映射的第一个虚拟页号，及其标志位
+    flags: BTreeMap<VirtualPageNumber, Flags>,
+}
+
+impl FlagsSnapshot {
+    /// 找出两个快照中不同的页面，依次给出页号及其在两边的标志位，`None` 表示没有映射
+    ///
+    /// 访问位和修改位由硬件维护，不参与比较
+    pub fn diff(
+        &self,
+        other: &FlagsSnapshot,
+    ) -> Vec<(VirtualPageNumber, Option<Flags>, Option<Flags>)> {
+        let hardware_flags = Flags::ACCESSED | Flags::DIRTY;
+        let mut vpns: Vec<VirtualPageNumber> = self
+            .flags
+            .keys()
+            .chain(other.flags.keys())
+            .copied()
+            .collect();
+        vpns.sort_unstable();
+        vpns.dedup();
+        vpns.into_iter()
+            .filter_map(|vpn| {
+                let left = self.flags.get(&vpn).map(|flags| *flags - hardware_flags);
+                let right = other.flags.get(&vpn).map(|flags| *flags - hardware_flags);
+                if left == right {
+                    None
+                } else {
+                    Some((vpn, left, right))
+                }
+            })
+            .collect()
+    }
+}
+
+/// 只输出分页模式、根页表和页表数量，而不是逐个页表的内容
+///
+/// 映射中的字段由 [`MemorySet`](crate::memory::MemorySet) 记录，需要时应输出它的 `Debug`
+impl fmt::Debug for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Mapping {{ mode: {:?}, root: {}, page tables: {}, remote harts: {:?} }}",
+            self.mode,
+            self.root_ppn,
+            self.page_tables.len(),
+            self.remote_harts
+        )
+    }
 }
 
 impl Mapping {
     /// 将当前的映射加载到 `satp` 寄存器并记录
+    ///
+    /// 在 debug 模式下，写入之前会检查当前的 pc 和 sp 在新的映射中仍然有效，见 [`Mapping::check_current_context`]
     pub fn activate(&self) {
-        // satp 低 27 位为页号，高 4 位为模式，8 表示 Sv39
-        let new_satp = self.root_ppn.0 | (8 << 60);
+        #[cfg(debug_assertions)]
+        self.check_current_context();
+        // satp 低 44 位为页号，高 4 位为模式
+        let new_satp = self.root_ppn.0 | (self.mode.bits() << 60);
+        Self::write_satp(new_satp);
+    }
+
+    /// 切换到 `next` 的映射，返回切换之前 `satp` 的值，用于调度时记录换下的地址空间
+    ///
+    /// 我们不使用 ASID，所有映射的 ASID 都是 0，因此任何两个映射都会在 TLB 中冲突：
+    /// 只要 `satp` 发生变化就刷新整个 TLB；`next` 已经在使用时既不写入 `satp` 也不刷新。
+    /// `sfence.vma` 紧跟在写入 `satp` 之后，它之前对页表的写入对之后的地址转换可见，
+    /// 并且之后的访存不会再使用旧映射的 TLB 项。与 [`Mapping::activate`] 一样，debug 模式下会检查当前的 pc 和 sp
+    pub fn switch_to(next: &Mapping) -> usize {
+        let old_satp = Self::read_satp();
+        let new_satp = next.root_ppn.0 | (next.mode.bits() << 60);
+        if old_satp != new_satp {
+            #[cfg(debug_assertions)]
+            next.check_current_context();
+            Self::write_satp(new_satp);
+        }
+        old_satp
+    }
+
+    /// 检查当前的 pc 和 sp 在这个映射中被翻译到与现在相同的物理地址，且分别可执行、可写，否则 panic
+    ///
+    /// 如果新的映射（例如有错误的 [`MemorySet::new_kernel`]）没有包含正在执行的代码或正在使用的栈，
+    /// 写入 `satp` 之后的下一条指令就会出错，而此时连异常处理也无法进行，只能看到机器卡住。
+    /// 在写入之前检查，可以把这种情况变成一个说明了原因的 panic。
+    ///
+    /// [`MemorySet::new_kernel`]: crate::memory::MemorySet::new_kernel
+    #[cfg(debug_assertions)]
+    fn check_current_context(&self) {
+        let pc: usize;
+        let sp: usize;
         unsafe {
-            // 将 new_satp 的值写到 satp 寄存器
-            llvm_asm!("csrw satp, $0" :: "r"(new_satp) :: "volatile");
+            llvm_asm!("auipc $0, 0" : "=r"(pc) ::: "volatile");
+            llvm_asm!("mv $0, sp" : "=r"(sp) ::: "volatile");
+        }
+        for &(name, va, flag) in [("pc", pc, Flags::EXECUTABLE), ("sp", sp, Flags::WRITABLE)].iter()
+        {
+            let va = VirtualAddress(va);
+            let current = Self::lookup(va);
+            let translated = self.translate(va);
+            let flags = self.entry_flags(VirtualPageNumber::floor(va));
+            assert!(
+                translated.is_some()
+                    && translated == current
+                    && flags.map_or(false, |flags| flags.contains(flag)),
+                "current {} {:#x} is not mapped correctly in the mapping to activate: \
+                 translates to {:x?} (currently {:x?}) with flags {:?}",
+                name,
+                va.0,
+                translated,
+                current,
+                flags
+            );
+        }
+    }
+
+    /// 硬件支持的最高分页模式，由 [`Mapping::probe_satp_mode`] 在第一次使用时探测
+    pub fn paging_mode() -> SatpMode {
+        *PROBED_SATP_MODE
+    }
+
+    /// 这个映射使用的分页模式
+    pub fn mode(&self) -> SatpMode {
+        self.mode
+    }
+
+    /// 探测硬件支持的最高分页模式（Sv48、Sv39 或 Bare）
+    ///
+    /// `satp` 是 WARL 寄存器，写入不支持的模式时整个写入无效，因此可以写入后读回判断。
+    /// 为了不影响正在运行的内核，探测 Sv48 时临时构建一个根页表，其首尾两项都指向当前的 Sv39 根页表，
+    /// 这样对于 Sv39 能够表示的地址，两种模式的翻译结果相同。探测完成后恢复原来的 `satp`。
+    ///
+    /// 必须在已经开启 Sv39 分页时调用；如果分页没有开启则返回 Bare。
+    pub fn probe_satp_mode() -> SatpMode {
+        let old_satp = Self::read_satp();
+        if old_satp >> 60 == SatpMode::Bare.bits() {
+            return SatpMode::Bare;
+        }
+        // 无法分配临时页表时，保守地认为只支持当前正在使用的 Sv39
+        let mut root_table = match FRAME_ALLOCATOR.lock().alloc_zeroed() {
+            Ok(frame) => PageTableTracker::new(frame, 0),
+            Err(_) => return SatpMode::Sv39,
+        };
+        let current_root = PhysicalPageNumber(old_satp & SATP_PPN_MASK);
+        root_table.entries[0] = PageTableEntry::new(current_root, Flags::VALID);
+        root_table.entries[PAGE_SIZE / 8 - 1] = PageTableEntry::new(current_root, Flags::VALID);
+        Self::write_satp(root_table.page_number().0 | (SatpMode::Sv48.bits() << 60));
+        let probed_satp = Self::read_satp();
+        Self::write_satp(old_satp);
+        if probed_satp >> 60 == SatpMode::Sv48.bits() {
+            SatpMode::Sv48
+        } else {
+            SatpMode::Sv39
+        }
+    }
+
+    /// 激活当前的映射并执行 `f`，结束后恢复原来的 `satp`
+    ///
+    /// 用于需要临时切换地址空间的代码，例如在 QEMU 中映射一个页面、
+    /// 通过其虚拟地址写入再读出，以验证完整的地址转换过程。
+    pub fn with_active<R>(&self, f: impl FnOnce() -> R) -> R {
+        let old_satp = Self::read_satp();
+        self.activate();
+        let result = f();
+        Self::write_satp(old_satp);
+        result
+    }
+
+    /// 将 `satp` 设为 Bare 模式以关闭地址转换，并刷新 TLB
+    ///
+    /// 用于调试时排除映射本身的问题。关闭后所有地址都会被当作物理地址，
+    /// 因此调用者的代码、栈以及之后访问的数据都必须位于与物理地址相同的虚拟地址上，
+    /// 否则在写入 `satp` 之后会立即出错。我们的内核运行在高地址，默认并不满足这一条件。
+    pub fn deactivate() {
+        // 模式 0 即 Bare，不进行地址转换
+        Self::write_satp(0);
+    }
+
+    /// 这个映射是否正被 `satp` 使用
+    pub fn is_active(&self) -> bool {
+        let satp = Self::read_satp();
+        satp >> 60 != 0 && PhysicalPageNumber(satp & SATP_PPN_MASK) == self.root_ppn
+    }
+
+    /// 检查 `satp` 正在使用的就是这个映射，否则 panic，只在 debug 模式下检查
+    ///
+    /// 比较 `satp` 中的分页模式、ASID 和根页表的页号。我们不使用 ASID，它应当总是 0。
+    /// 用于在中断处理中发现进程使用了其他进程页表的调度错误。
+    pub fn assert_active(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let satp = Self::read_satp();
+        let mode = satp >> 60;
+        let asid = (satp >> 44) & 0xffff;
+        let root = PhysicalPageNumber(satp & SATP_PPN_MASK);
+        assert!(
+            mode == self.mode.bits() && asid == 0 && root == self.root_ppn,
+            "satp {:#x} (mode {}, asid {}, root {}) does not match the mapping ({:?}, root {})",
+            satp,
+            mode,
+            asid,
+            root,
+            self.mode,
+            self.root_ppn
+        );
+    }
+
+    /// 检查根页表仍然位于 `page_tables[0]`
+    ///
+    /// 如果在映射被 `satp` 使用时移除或替换了根页表，地址转换会直接失效，
+    /// 表现为难以追查的连续异常，而不是一个明确的错误，因此在每次修改 `page_tables` 后检查。
+    fn debug_assert_root_kept(&self) {
+        debug_assert!(
+            !self.is_active()
+                || self.page_tables.first().map(|table| table.page_number()) == Some(self.root_ppn),
+            "root page table pulled out from under the active mapping"
+        );
+    }
+
+    /// 当前是否开启了分页（`satp` 模式不为 Bare）
+    pub fn is_paging_enabled() -> bool {
+        Self::read_satp() >> 60 != 0
+    }
+
+    /// 读取 `satp` 寄存器
+    fn read_satp() -> usize {
+        let satp;
+        unsafe { llvm_asm!("csrr $0, satp" : "=r"(satp) ::: "volatile") };
+        satp
+    }
+
+    /// 写入 `satp` 寄存器并刷新 TLB
+    fn write_satp(satp: usize) {
+        unsafe {
+            // 将 satp 的值写到 satp 寄存器
+            llvm_asm!("csrw satp, $0" :: "r"(satp) :: "volatile");
             // 刷新 TLB
             llvm_asm!("sfence.vma" :::: "volatile");
         }
     }
 
-    /// 创建一个有根节点的映射
+    /// 创建一个有根节点的 Sv39 映射
     pub fn new() -> MemoryResult<Mapping> {
-        let root_table = PageTableTracker::new(FRAME_ALLOCATOR.lock().alloc()?);
+        Self::with_mode(SatpMode::Sv39)
+    }
+
+    /// 创建一个有根节点、使用给定分页模式的映射
+    ///
+    /// Sv48 需要硬件支持（见 [`Mapping::paging_mode`]），否则返回 `Err`
+    pub fn with_mode(mode: SatpMode) -> MemoryResult<Mapping> {
+        match mode {
+            SatpMode::Sv39 => {}
+            SatpMode::Sv48 if Self::paging_mode() == SatpMode::Sv48 => {}
+            _ => return Err("paging mode is not supported"),
+        }
+        let root_table = PageTableTracker::new(FRAME_ALLOCATOR.lock().alloc_zeroed()?, 0);
         let root_ppn = root_table.page_number();
         Ok(Mapping {
-            page_tables: vec![root_table],
+            page_tables: vec![Arc::new(root_table)],
+            kernel_slots: Vec::new(),
             root_ppn,
+            mode,
+            shootdown: None,
+            remote_harts: Vec::new(),
+            oom_handler: None,
+            batch: None,
+            generation: 0,
+            default_flags: Flags::empty(),
         })
     }
 
+    /// 设置之后通过 [`MemorySet`](crate::memory::MemorySet) 新建字段时自动加上的标志位，默认为空
+    ///
+    /// 例如用户进程的地址空间设置 `USER`，避免某处映射时漏掉它。`USER` 只会加到用户地址空间中的字段上，
+    /// `GLOBAL` 只会加到内核地址空间中的字段上，见 [`Mapping::default_flags_for`]。已经建立的字段不受影响。
+    pub fn set_default_flags(&mut self, flags: Flags) {
+        self.default_flags = flags;
+    }
+
+    /// 通过 [`Mapping::set_default_flags`] 设置的标志位
+    pub fn default_flags(&self) -> Flags {
+        self.default_flags
+    }
+
+    /// 在 `range` 处新建字段时应当自动加上的标志位
+    ///
+    /// 从 [`Mapping::set_default_flags`] 设置的标志位中，去掉与所在的那一半地址空间不符的 `USER` 或 `GLOBAL`
+    pub fn default_flags_for(&self, range: Range<VirtualAddress>) -> Flags {
+        let mut flags = self.default_flags;
+        if range.end > USER_ADDRESS_MAX {
+            flags.remove(Flags::USER);
+        }
+        if range.start < KERNEL_ADDRESS_MIN {
+            flags.remove(Flags::GLOBAL);
+        }
+        flags
+    }
+
+    /// 最后一级页表的级数，级数从根页表的 0 开始
+    fn leaf_level(&self) -> usize {
+        self.mode.levels() - 1
+    }
+
+    /// 根页表的物理页号
+    pub fn root_ppn(&self) -> PhysicalPageNumber {
+        self.root_ppn
+    }
+
+    /// 所有页表所在的物理页号，包括根页表
+    pub fn page_table_ppns(&self) -> impl Iterator<Item = PhysicalPageNumber> + '_ {
+        self.page_tables.iter().map(|table| table.page_number())
+    }
+
+    /// 每一级的页表数量，下标为级数，根页表为第 0 级
+    ///
+    /// 用于衡量页表本身占用的内存，例如比较合并大页前后的差别。Sv39 只有三级，最后一项总是 0
+    pub fn page_table_stats(&self) -> [usize; 4] {
+        let mut counts = [0; 4];
+        for table in self.page_tables.iter() {
+            counts[table.level()] += 1;
+        }
+        counts
+    }
+
+    /// 按深度优先的顺序遍历所有非空的页表项
+    ///
+    /// 需要检查整个页表树的功能（例如 [`Mapping::validate`]、[`Mapping::snapshot_flags`]）都基于它实现；
+    /// 只查找一个页面的功能（例如 [`Mapping::translate`]）仍然沿着单一路径向下查找。
+    pub fn walker(&self) -> PageTableWalker {
+        let root_ppn = if self.page_tables.is_empty() {
+            // 通过 Default 创建的映射没有根页表
+            None
+        } else {
+            Some(self.root_ppn)
+        };
+        PageTableWalker::new(self, root_ppn)
+    }
+
+    /// 开始批量修改映射，在返回的 [`FlushBatch`] 被 drop 之前，修改页表项后不会立即刷新 TLB
+    ///
+    /// 例如连续取消映射大量页面时，只需在最后刷新一次。批次可以嵌套，以最外层的结束为准。
+    pub fn begin_batch(&mut self) -> FlushBatch {
+        let outermost = self.batch.is_none();
+        if outermost {
+            self.batch = Some(Vec::new());
+        }
+        FlushBatch {
+            mapping: self,
+            outermost,
+        }
+    }
+
+    /// 设置通知其他核刷新 TLB 的方式，多核时必须在映射被共享之前设置
+    pub fn set_shootdown(&mut self, shootdown: Arc<dyn TlbShootdown>) {
+        self.shootdown = Some(shootdown);
+    }
+
+    /// 设置分配物理帧失败时的处理方式，之后映射页面、复制页面和新建页表时都会经过它
+    pub fn set_oom_handler(&mut self, handler: Arc<dyn OomHandler>) {
+        self.oom_handler = Some(handler);
+    }
+
+    /// 分配一个清零的帧，失败时询问 [`OomHandler`]，还需要的帧数为 `needed`
+    ///
+    /// 处理方式返回 [`OomAction::Retry`] 时重新分配，返回 [`OomAction::Kill`] 时返回 [`OOM_KILLED`]
+    pub(super) fn alloc_frame(&self, needed: usize) -> MemoryResult<FrameTracker> {
+        self.retry_alloc(needed, || FRAME_ALLOCATOR.lock().alloc_zeroed())
+    }
+
+    /// 与 [`Mapping::alloc_frame`] 相同，但不清零，用于随后会被完整覆盖的帧（例如复制页面）
+    pub(super) fn alloc_frame_uninit(&self, needed: usize) -> MemoryResult<FrameTracker> {
+        self.retry_alloc(needed, || FRAME_ALLOCATOR.lock().alloc())
+    }
+
+    /// 分配物理上连续的 `count` 个帧，第一个帧的物理页号是 `align` 的倍数，失败时同样询问 [`OomHandler`]
+    ///
+    /// 返回的帧按物理页号递增排列，内容没有清零
+    pub(super) fn alloc_contiguous(
+        &self,
+        count: usize,
+        align: usize,
+    ) -> MemoryResult<Vec<FrameTracker>> {
+        self.retry_alloc(count, || {
+            FRAME_ALLOCATOR.lock().alloc_contiguous(count, align)
+        })
+    }
+
+    /// 反复调用 `alloc`，直到成功或者处理方式决定放弃
+    ///
+    /// 每次分配都在 `alloc` 内部加锁并在返回前释放，询问处理方式时分配器没有被锁住，它可以回收帧。
+    fn retry_alloc<T>(
+        &self,
+        needed: usize,
+        mut alloc: impl FnMut() -> MemoryResult<T>,
+    ) -> MemoryResult<T> {
+        loop {
+            let message = match alloc() {
+                Ok(result) => return Ok(result),
+                Err(message) => message,
+            };
+            match self
+                .oom_handler
+                .as_ref()
+                .map(|handler| handler.on_oom(needed))
+            {
+                Some(OomAction::Retry) => continue,
+                Some(OomAction::Kill) => return Err(OOM_KILLED),
+                Some(OomAction::Fail) | None => return Err(message),
+            }
+        }
+    }
+
+    /// 记录映射在另一个核上也生效，之后对页表项的修改会通知该核
+    pub fn add_remote_hart(&mut self, hart: usize) {
+        if !self.remote_harts.contains(&hart) {
+            self.remote_harts.push(hart);
+        }
+    }
+
+    /// 映射不再在给定的核上生效
+    pub fn remove_remote_hart(&mut self, hart: usize) {
+        self.remote_harts.retain(|&h| h != hart);
+    }
+
     /// 加入一段映射，可能会相应地分配物理页面
     ///
     /// 未被分配物理页面的虚拟页号暂时不会写入页表当中，它们会在发生 PageFault 后再建立页表项。
@@ -57,6 +586,10 @@ impl Mapping {
         match segment.map_type {
             // 线性映射，直接对虚拟地址进行转换
             MapType::Linear => {
+                if !segment.linear_in_bounds() {
+                    println!("linear segment {:x?} maps outside RAM", segment.range);
+                    return Err("linear mapping targets physical memory outside RAM");
+                }
                 for vpn in segment.page_range().iter() {
                     self.map_one(vpn, vpn.into(), segment.flags | Flags::VALID)?;
                 }
@@ -69,18 +602,48 @@ impl Mapping {
                 }
                 Ok(Vec::new())
             }
+            // 设备 ROM 和共用的页面，映射到给定的物理页面，不能写入初始数据
+            MapType::Rom(_) | MapType::Shared(_) => {
+                if init_data.is_some() {
+                    return Err("cannot write initial data into a segment of fixed frames");
+                }
+                for (vpn, ppn) in segment
+                    .page_range()
+                    .iter()
+                    .zip(segment.iter_mapped().unwrap())
+                {
+                    self.map_one(vpn, ppn, segment.flags | Flags::VALID)?;
+                }
+                Ok(Vec::new())
+            }
             // 需要分配帧进行映射
-            MapType::Framed => {
-                // 记录所有成功分配的页面映射
-                let mut allocated_pairs = Vec::new();
+            MapType::Framed | MapType::FramedWithFlags(_) | MapType::ShadowStack => {
+                // 记录所有成功分配的页面映射，预先分配好空间，避免映射大段区间时反复扩容
+                let page_count = segment.page_range().len();
+                let mut allocated_pairs = Vec::with_capacity(page_count);
+                let available = FRAME_ALLOCATOR.lock().free_count();
                 for vpn in segment.page_range().iter() {
-                    // 分配物理页面
-                    let mut frame = FRAME_ALLOCATOR.lock().alloc()?;
-                    // 映射，填充 0，记录
-                    self.map_one(vpn, frame.page_number(), segment.flags | Flags::VALID)?;
-                    frame.fill(0);
+                    // 分配清零的物理页面，失败时打印需要和剩余的帧数，便于排查
+                    let needed = page_count - allocated_pairs.len();
+                    let frame = self.alloc_frame(needed).map_err(|message| {
+                        println!(
+                            "out of frames mapping {:x?}: requested {}, available {}, mapped {}",
+                            segment.range,
+                            page_count,
+                            available,
+                            allocated_pairs.len()
+                        );
+                        message
+                    })?;
+                    // 映射，记录
+                    self.map_one(
+                        vpn,
+                        frame.page_number(),
+                        segment.flags_of(vpn) | Flags::VALID,
+                    )?;
                     allocated_pairs.push((vpn, frame));
                 }
+                debug_assert_eq!(allocated_pairs.len(), page_count);
 
                 // 拷贝数据，注意页表尚未应用，无法直接从刚刚映射的虚拟地址访问，因此必须用物理地址 + 偏移来访问。
                 if let Some(data) = init_data {
@@ -110,59 +673,257 @@ impl Mapping {
 
                 Ok(allocated_pairs)
             }
+            // 别名需要知道被共用的帧，无法在这里映射
+            MapType::Alias => Err("alias segment can only be mapped by MemorySet::map_alias"),
+            // 按需分配和文件映射在缺页时才分配页面，此时也不创建页表
+            MapType::Lazy | MapType::FileBacked(_) => Ok(Vec::new()),
         }
     }
 
     /// 移除一段映射
-    pub fn unmap(&mut self, segment: &Segment) {
+    ///
+    /// 对于缺页时才映射的字段，跳过尚未映射的页面
+    pub fn unmap(&mut self, segment: &Segment) -> MemoryResult<()> {
+        // 即使按需映射的页面都没有映射，字段本身被移除也会改变之后的访问检查
+        self.generation += 1;
+        let mut mapping = self.begin_batch();
         for vpn in segment.page_range().iter() {
-            let entry = self.find_entry(vpn).unwrap();
-            assert!(!entry.is_empty());
-            // 从页表中清除项
-            entry.clear();
+            if segment.map_type.is_lazy() && !mapping.is_mapped(vpn) {
+                continue;
+            }
+            mapping.unmap_one(vpn)?;
+        }
+        Ok(())
+    }
+
+    /// 给定虚拟页号是否已经映射，不会创建页表
+    pub fn is_mapped(&self, vpn: VirtualPageNumber) -> bool {
+        match self.find_existing_entry(vpn) {
+            Some(entry) => !entry.is_empty(),
+            None => false,
         }
     }
 
-    /// 找到给定虚拟页号的三级页表项
+    /// 找到映射给定虚拟页号的页表项，不会创建页表
+    ///
+    /// 通常是最后一级的页表项，如果虚拟页号位于大页中，则是对应大页的叶子页表项。
+    /// 如果中间的页表不存在，则返回 `None`
+    fn find_existing_entry(&self, vpn: VirtualPageNumber) -> Option<&'static mut PageTableEntry> {
+        self.find_existing_leaf(vpn).map(|(entry, _level)| entry)
+    }
+
+    /// 同 [`Mapping::find_existing_entry`]，同时返回页表项所在的级数（0 为根页表）
+    fn find_existing_leaf(
+        &self,
+        vpn: VirtualPageNumber,
+    ) -> Option<(&'static mut PageTableEntry, usize)> {
+        let levels = vpn.levels(self.mode.levels());
+        let root_table: &mut PageTable = PhysicalAddress::from(self.root_ppn).deref_kernel();
+        let mut entry = &mut root_table.entries[levels[0]];
+        for (level, vpn_slice) in levels[1..].iter().enumerate() {
+            if entry.is_empty() {
+                return None;
+            }
+            if !entry.has_next_level() {
+                // 大页
+                return Some((entry, level));
+            }
+            entry = &mut entry.get_next_table().entries[*vpn_slice];
+        }
+        Some((entry, self.leaf_level()))
+    }
+
+    /// 查找给定虚拟页号时经过的每一级页表项，依次为 `(级数, 下标, 页表项)`，根页表为第 0 级
+    ///
+    /// 用于排查某个地址的转换问题：遇到空的页表项（也包括在内）或叶子页表项时停止，
+    /// 因此最后一项就是转换中断或结束的位置。不会创建页表
+    pub fn walk_to(&self, vpn: VirtualPageNumber) -> Vec<(usize, usize, PageTableEntry)> {
+        let mut path = Vec::with_capacity(self.mode.levels());
+        if self.page_tables.is_empty() {
+            return path;
+        }
+        let mut table: &PageTable = PhysicalAddress::from(self.root_ppn).deref_kernel();
+        for (level, &index) in vpn.levels(self.mode.levels()).iter().enumerate() {
+            let entry = table.entries[index];
+            path.push((level, index, entry));
+            if entry.is_empty() || level == self.leaf_level() || !entry.has_next_level() {
+                break;
+            }
+            table = entry.get_next_table();
+        }
+        path
+    }
+
+    /// 给定虚拟页号所在的叶子页表项的标志位，没有映射则返回 `None`
+    ///
+    /// 与 [`Mapping::find_entry`] 不同，不会创建页表，页面位于大页中时返回大页的标志位
+    pub fn entry_flags(&self, vpn: VirtualPageNumber) -> Option<Flags> {
+        self.find_existing_leaf(vpn)
+            .filter(|(entry, _level)| !entry.is_empty())
+            .map(|(entry, _level)| entry.flags())
+    }
+
+    /// 访问虚拟地址时实际生效的标志位，即沿查找路径找到的叶子页表项的标志位，没有有效的映射则返回 `None`
+    ///
+    /// RISC-V 中间级的页表项不影响权限，权限完全由叶子页表项决定，但叶子可能位于任何一级（大页）。
+    /// 只查找最后一级页表的做法会漏掉大页。与 [`Mapping::entry_flags`] 不同，没有 `VALID` 位的页表项视为没有映射
+    pub fn effective_flags(&self, va: VirtualAddress) -> Option<Flags> {
+        self.entry_flags(VirtualPageNumber::floor(va))
+            .filter(|flags| flags.contains(Flags::VALID))
+    }
+
+    /// 如果给定虚拟页号位于大页中，则将其拆分，使之由单独的最后一级页表项映射
+    fn split_if_huge(&mut self, vpn: VirtualPageNumber) -> MemoryResult<()> {
+        match self.find_existing_leaf(vpn) {
+            Some((_entry, level)) if level < self.leaf_level() => self.split_huge_page(vpn),
+            _ => Ok(()),
+        }
+    }
+
+    /// 将包含 `vpn` 的 2M 大页拆分为 512 个 4K 页面，保留所有页面原有的映射和标志位
+    ///
+    /// 新建一个最后一级的页表，将原来的叶子页表项替换为指向它的页表项，并刷新 TLB。
+    /// 如果 `vpn` 位于更大的大页（1G，或者 Sv48 中的 512G）中，会逐级拆分。
+    /// 如果 `vpn` 没有映射，或者已经由最后一级的页表项映射，则返回 `Err`。
+    pub fn split_huge_page(&mut self, vpn: VirtualPageNumber) -> MemoryResult<()> {
+        self.check_not_shared(vpn)?;
+        let levels = vpn.levels(self.mode.levels());
+        let leaf_level = self.leaf_level();
+        let root_table: &mut PageTable = PhysicalAddress::from(self.root_ppn).deref_kernel();
+        let mut entry = &mut root_table.entries[levels[0]];
+        let mut split = false;
+        for level in 0..leaf_level {
+            if entry.is_empty() {
+                return Err("virtual address to split is not mapped");
+            }
+            if !entry.has_next_level() {
+                // 下一级的每一项覆盖的页面数，例如 1G 大页拆为 2M 大页，2M 大页拆为 4K 页面
+                let step = 1 << (9 * (leaf_level - 1 - level));
+                let mut new_table = PageTableTracker::new(self.alloc_frame(1)?, level + 1);
+                let (ppn, flags) = (entry.page_number(), entry.flags());
+                for (index, child) in new_table.entries.iter_mut().enumerate() {
+                    *child = PageTableEntry::new(ppn + index * step, flags);
+                }
+                *entry = PageTableEntry::new(new_table.page_number(), Flags::VALID);
+                self.page_tables.push(Arc::new(new_table));
+                self.debug_assert_root_kept();
+                split = true;
+            }
+            entry = &mut entry.get_next_table().entries[levels[level + 1]];
+        }
+        if !split {
+            return Err("virtual address to split is not in a huge page");
+        }
+        // 拆分前后的映射相同，刷新的目的是不再使用大页的 TLB 项
+        self.flush(vpn);
+        debug_assert_eq!(self.validate_page(vpn), Ok(()));
+        Ok(())
+    }
+
+    /// 找到给定虚拟页号的最后一级页表项
     ///
     /// 如果找不到对应的页表项，则会相应创建页表
     pub fn find_entry(&mut self, vpn: VirtualPageNumber) -> MemoryResult<&mut PageTableEntry> {
+        let leaf_level = self.leaf_level();
+        self.find_entry_at(vpn, leaf_level)
+    }
+
+    /// 找到给定虚拟页号在第 `target` 级页表中的页表项，根页表为第 0 级
+    ///
+    /// 如果途中的页表不存在，则会相应创建页表
+    fn find_entry_at(
+        &mut self,
+        vpn: VirtualPageNumber,
+        target: usize,
+    ) -> MemoryResult<&mut PageTableEntry> {
+        self.check_not_shared(vpn)?;
+        let levels = vpn.levels(self.mode.levels());
         // 从根页表开始向下查询
         // 这里不用 self.page_tables[0] 避免后面产生 borrow-check 冲突（我太菜了）
         let root_table: &mut PageTable = PhysicalAddress::from(self.root_ppn).deref_kernel();
-        let mut entry = &mut root_table.entries[vpn.levels()[0]];
-        for vpn_slice in &vpn.levels()[1..] {
+        let mut entry = &mut root_table.entries[levels[0]];
+        for (level, vpn_slice) in levels.iter().enumerate().skip(1).take(target) {
+            if !entry.is_empty() && !entry.has_next_level() {
+                return Err("virtual address is inside a huge page");
+            }
             if entry.is_empty() {
-                // 如果页表不存在，则需要分配一个新的页表
-                let new_table = PageTableTracker::new(FRAME_ALLOCATOR.lock().alloc()?);
+                // 如果页表不存在，则需要分配一个新的第 level 级页表
+                let new_table = PageTableTracker::new(self.alloc_frame(1)?, level);
                 let new_ppn = new_table.page_number();
                 // 将新页表的页号写入当前的页表项
                 *entry = PageTableEntry::new(new_ppn, Flags::VALID);
                 // 保存页表
-                self.page_tables.push(new_table);
+                self.page_tables.push(Arc::new(new_table));
+                self.debug_assert_root_kept();
             }
-            // 进入下一级页表（使用偏移量来访问物理地址）
+            // 进入下一级页表（通过线性映射访问物理地址）
             entry = &mut entry.get_next_table().entries[*vpn_slice];
         }
-        // 此时 entry 位于第三级页表
+        // 此时 entry 位于第 target 级页表
         Ok(entry)
     }
 
-    /// 查找虚拟地址对应的物理地址
+    /// 在当前 `satp` 指向的页表中查找虚拟地址对应的物理地址
+    ///
+    /// Bare 模式下不进行地址转换，直接返回相同的地址
     pub fn lookup(va: VirtualAddress) -> Option<PhysicalAddress> {
-        let mut current_ppn;
-        unsafe {
-            llvm_asm!("csrr $0, satp" : "=r"(current_ppn) ::: "volatile");
-            current_ppn ^= 8 << 60;
-        }
+        let satp = Self::read_satp();
+        let levels = match satp >> 60 {
+            0 => return Some(PhysicalAddress(va.0)),
+            9 => SatpMode::Sv48.levels(),
+            _ => SatpMode::Sv39.levels(),
+        };
+        Self::walk(PhysicalPageNumber(satp & SATP_PPN_MASK), levels, va)
+    }
 
-        let root_table: &PageTable =
-            PhysicalAddress::from(PhysicalPageNumber(current_ppn)).deref_kernel();
+    /// 在这个映射中查找虚拟地址对应的物理地址，不要求映射已被激活
+    ///
+    /// 与 [`Mapping::find_entry`] 不同，这里不会创建页表，缺少中间页表或页表项时返回 `None`
+    pub fn translate(&self, va: VirtualAddress) -> Option<PhysicalAddress> {
+        Self::walk(self.root_ppn, self.mode.levels(), va)
+    }
+
+    /// 映射的版本号，每当已有的翻译可能改变时（取消映射、修改页表项、拆分或合并大页、移除字段）增加
+    ///
+    /// 调用者可以将 [`Mapping::translate`] 的结果与当时的版本号一起缓存，之后版本号没有变化时直接使用，
+    /// 例如系统调用在让出处理器前后都要访问同一个用户缓冲区时。新建映射不会使已有的翻译失效，因此不改变版本号。
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// 查找虚拟地址对应的物理地址，同时给出当时的版本号，见 [`Mapping::generation`]
+    pub fn translate_with_generation(&self, va: VirtualAddress) -> Option<(PhysicalAddress, u64)> {
+        self.translate(va).map(|pa| (pa, self.generation))
+    }
+
+    /// 计算一个已经映射的页面内容的 64 位哈希值，没有映射则返回 `None`
+    ///
+    /// 通过线性映射读取页面所在的帧，按 8 字节为单位进行 FNV-1a 式的混合，不依赖其他库。
+    /// 用于寻找内容可能相同的页面：哈希值相同时仍需逐字节比较，确认后再用
+    /// [`MemorySet::replace_frame`](crate::memory::MemorySet::replace_frame) 合并。
+    pub fn hash_page(&self, vpn: VirtualPageNumber) -> Option<u64> {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+        let pa = self.translate(VirtualAddress::from(vpn))?;
+        let words: &[u64; PAGE_SIZE / 8] = pa.deref_kernel();
+        Some(words.iter().fold(FNV_OFFSET, |hash, word| {
+            (hash ^ word).wrapping_mul(FNV_PRIME)
+        }))
+    }
+
+    /// 从给定的根页表开始，按照 `levels` 级页表查找虚拟地址对应的物理地址
+    fn walk(
+        root_ppn: PhysicalPageNumber,
+        levels: usize,
+        va: VirtualAddress,
+    ) -> Option<PhysicalAddress> {
+        let root_table: &PageTable = PhysicalAddress::from(root_ppn).deref_kernel();
         let vpn = VirtualPageNumber::floor(va);
-        let mut entry = &root_table.entries[vpn.levels()[0]];
+        let levels = vpn.levels(levels);
+        let mut entry = &root_table.entries[levels[0]];
         // 为了支持大页的查找，我们用 length 表示查找到的物理页需要加多少位的偏移
-        let mut length = 12 + 2 * 9;
-        for vpn_slice in &vpn.levels()[1..] {
+        let mut length = 12 + (levels.len() - 1) * 9;
+        for vpn_slice in &levels[1..] {
             if entry.is_empty() {
                 return None;
             }
@@ -173,23 +934,666 @@ impl Mapping {
                 break;
             }
         }
+        // 最后一级的页表项也可能为空
+        if entry.is_empty() {
+            return None;
+        }
         let base = PhysicalAddress::from(entry.page_number()).0;
         let offset = va.0 & ((1 << length) - 1);
         Some(PhysicalAddress(base + offset))
     }
 
+    /// 找到查找路径上第 `level` 级的页表项，路径上的页表必须存在
+    fn entry_at(&self, levels: &[usize], level: usize) -> &'static mut PageTableEntry {
+        let root_table: &mut PageTable = PhysicalAddress::from(self.root_ppn).deref_kernel();
+        let mut entry = &mut root_table.entries[levels[0]];
+        for vpn_slice in &levels[1..=level] {
+            assert!(!entry.is_empty(), "virtual address is not mapped");
+            entry = &mut entry.get_next_table().entries[*vpn_slice];
+        }
+        entry
+    }
+
+    /// 移除一个虚拟页号的映射
+    ///
+    /// 如果中间的页表因此变为空，则一并释放，并清除上一级中指向它的页表项。
+    /// 否则反复映射、取消映射会使页表不断累积，直到整个映射被 drop 才能释放。
+    ///
+    /// 如果页面位于大页中，会先拆分大页，其余页面的映射不受影响。拆分时可能因无法分配页表而返回 `Err`。
+    pub(super) fn unmap_one(&mut self, vpn: VirtualPageNumber) -> MemoryResult<()> {
+        self.check_not_shared(vpn)?;
+        self.split_if_huge(vpn)?;
+        let levels = vpn.levels(self.mode.levels());
+        let leaf_level = self.leaf_level();
+        let entry = self.entry_at(&levels, leaf_level);
+        assert!(!entry.is_empty(), "virtual address is not mapped");
+        // 从页表中清除项
+        entry.clear();
+        self.flush(vpn);
+        self.free_empty_tables(&levels, leaf_level);
+        debug_assert_eq!(self.validate_page(vpn), Ok(()));
+        Ok(())
+    }
+
+    /// 自下而上释放查找路径上因移除第 `level` 级的页表项而变为空的页表
+    ///
+    /// 先清除上一级中指向这些页表的页表项并刷新整个 TLB，然后才释放页表：
+    /// 按虚拟地址刷新只保证丢弃叶子页表项，硬件仍可能缓存指向中间页表的项，
+    /// 如果页表先被释放并重新分配，之后的地址转换就可能经过一个内容已经改变的页面。
+    fn free_empty_tables(&mut self, levels: &[usize], level: usize) {
+        let mut freed = Vec::new();
+        for parent_level in (0..level).rev() {
+            let parent = self.entry_at(levels, parent_level);
+            if !parent.get_next_table().is_empty() {
+                break;
+            }
+            freed.push(parent.page_number());
+            parent.clear();
+        }
+        if freed.is_empty() {
+            return;
+        }
+        self.flush_all();
+        for ppn in freed {
+            self.free_table(ppn);
+        }
+    }
+
+    /// 移除映射给定虚拟页号的叶子页表项，返回被移除的页面数，没有映射时返回 0
+    ///
+    /// 与 [`Mapping::unmap_one`] 不同，大页被整个移除而不拆分，因此不会分配页表，
+    /// 用于映射字段中途失败时撤销已经建立的映射
+    pub(super) fn unmap_leaf(&mut self, vpn: VirtualPageNumber) -> MemoryResult<usize> {
+        self.check_not_shared(vpn)?;
+        let level = match self.find_existing_leaf(vpn) {
+            Some((entry, level)) if !entry.is_empty() => {
+                entry.clear();
+                level
+            }
+            _ => return Ok(0),
+        };
+        self.flush(vpn);
+        self.free_empty_tables(&vpn.levels(self.mode.levels()), level);
+        Ok(1 << (9 * (self.leaf_level() - level)))
+    }
+
+    /// 尝试将包含 `vpn` 的 2M 对齐区间内的 512 个页面合并为一个大页，以减少 TLB 的占用
+    ///
+    /// 只有当这些页面全部映射、标志位相同（不计 ACCESSED 和 DIRTY），且物理页号连续并 2M 对齐时才会合并，
+    /// 此时释放原来的最后一级页表并返回 `true`；否则不做修改，返回 `false`。
+    /// 合并只改变页表，物理帧仍由原来的持有者管理。
+    pub fn try_promote_huge(&mut self, vpn: VirtualPageNumber) -> MemoryResult<bool> {
+        const PAGES: usize = PAGE_SIZE / 8;
+        self.check_not_shared(vpn)?;
+        let levels = vpn.levels(self.mode.levels());
+        let root_table: &mut PageTable = PhysicalAddress::from(self.root_ppn).deref_kernel();
+        // 找到指向最后一级页表的页表项
+        let mut entry = &mut root_table.entries[levels[0]];
+        for vpn_slice in &levels[1..self.leaf_level()] {
+            if entry.is_empty() || !entry.has_next_level() {
+                return Ok(false);
+            }
+            entry = &mut entry.get_next_table().entries[*vpn_slice];
+        }
+        if entry.is_empty() || !entry.has_next_level() {
+            return Ok(false);
+        }
+        let last_table = entry.get_next_table();
+        // 以第一个页面为准检查其余页面
+        let first = last_table.entries[0];
+        let first_ppn = first.page_number();
+        let ignored = Flags::ACCESSED | Flags::DIRTY;
+        if first.is_empty() || !first.flags().contains(Flags::VALID) || first_ppn.0 % PAGES != 0 {
+            return Ok(false);
+        }
+        let mut flags = first.flags();
+        for (index, page) in last_table.entries.iter().enumerate() {
+            if page.is_empty()
+                || page.flags() - ignored != first.flags() - ignored
+                || page.page_number() != first_ppn + index
+            {
+                return Ok(false);
+            }
+            flags |= page.flags();
+        }
+        // 替换为大页的叶子页表项，并释放最后一级页表
+        let table_ppn = entry.page_number();
+        *entry = PageTableEntry::new(first_ppn, flags);
+        self.free_table(table_ppn);
+        self.generation += 1;
+        self.flush_all();
+        debug_assert_eq!(self.validate_page(vpn), Ok(()));
+        Ok(true)
+    }
+
+    /// 让映射的内核高半部分直接使用 `kernel` 的页表，而不是各自复制一份
+    ///
+    /// 只复制 `kernel` 根页表高半部分中的页表项，它们指向的各级页表由两个映射共用：
+    /// 本映射为这些页表各保存一个 `Arc` 引用，因此 drop 时只减少引用计数，
+    /// 页表由最后一个引用它的映射释放。内核映射之后在这些页表中的修改对本映射同样可见，
+    /// 但在根页表中新增的页表项不会同步，因此应当在内核映射建立完成之后再共用。
+    ///
+    /// 共用的部分对本映射只读：之后在其中映射、取消映射或修改页表项都会返回 `Err`。
+    /// 两个映射的分页模式必须相同，本映射的高半部分必须为空。
+    pub fn link_kernel_subtree(&mut self, kernel: &Mapping) -> MemoryResult<()> {
+        if self.mode != kernel.mode {
+            return Err("kernel mapping uses a different paging mode");
+        }
+        let entries = PAGE_SIZE / 8;
+        let root_table: &mut PageTable = PhysicalAddress::from(self.root_ppn).deref_kernel();
+        if root_table.entries[entries / 2..]
+            .iter()
+            .any(|entry| !entry.is_empty())
+        {
+            return Err("kernel half of the mapping is already in use");
+        }
+        let mut slots = Vec::new();
+        for step in kernel.walker() {
+            // 由各级下标得到根页表中的下标
+            let slot = step.vpn_prefix >> (9 * step.level);
+            if slot < entries / 2 {
+                continue;
+            }
+            if step.level == 0 {
+                root_table.entries[slot] = *step.entry;
+                slots.push(slot);
+            }
+            if step.level < kernel.leaf_level() && step.entry.has_next_level() {
+                let ppn = step.entry.page_number();
+                let table = kernel
+                    .page_tables
+                    .iter()
+                    .find(|table| table.page_number() == ppn)
+                    .ok_or("page table entry points to an untracked page table")?;
+                self.page_tables.push(table.clone());
+            }
+        }
+        self.kernel_slots = slots;
+        self.generation += 1;
+        self.flush_all();
+        Ok(())
+    }
+
+    /// 将 `kernel` 中内核 `.text` 段所在的物理帧以 `r-x`、全局的权限映射到本映射的相同地址，不分配新的帧
+    ///
+    /// 用于多核启动时每个核各自建立映射，但共用内存中唯一的一份内核代码。
+    /// 映射只拥有自己的页表，叶子页表项指向的帧并不属于它，这些帧也从未交给任何 [`FrameTracker`]，
+    /// 因此 drop 时只释放本映射的页表，不会重复释放内核代码所在的帧。
+    /// `.text` 的页面在 `kernel` 中必须都已映射，在本映射中必须都未映射，否则返回 `Err` 而不做任何映射。
+    /// 中途无法分配页表时，撤销已经建立的映射再返回 `Err`
+    pub fn map_kernel_shared_text(&mut self, kernel: &Mapping) -> MemoryResult<()> {
+        // 在 linker.ld 里面标记的 .text 段的起止，均为 4K 对齐
+        extern "C" {
+            fn text_start();
+            fn rodata_start();
+        }
+        let text = Range::<VirtualAddress>::from((text_start as usize)..(rodata_start as usize))
+            .to_page_range();
+        if text.iter().any(|vpn| self.is_mapped(vpn)) {
+            return Err("kernel text is already mapped");
+        }
+        let frames = text
+            .iter()
+            .map(|vpn| kernel.translate(vpn.into()).map(PhysicalPageNumber::floor))
+            .collect::<Option<Vec<_>>>()
+            .ok_or("kernel text is not mapped in the kernel mapping")?;
+        let flags = Flags::RX | Flags::GLOBAL;
+        for (index, (vpn, ppn)) in text.iter().zip(frames).enumerate() {
+            if let Err(message) = self.map_one(vpn, ppn, flags) {
+                for vpn in text.iter().take(index) {
+                    self.unmap_leaf(vpn)?;
+                }
+                return Err(message);
+            }
+        }
+        Ok(())
+    }
+
+    /// 给定虚拟页号是否位于与内核映射共用的页表中，见 [`Mapping::link_kernel_subtree`]
+    fn check_not_shared(&self, vpn: VirtualPageNumber) -> MemoryResult<()> {
+        let slot = vpn.levels(self.mode.levels())[0];
+        if self.kernel_slots.contains(&slot) {
+            Err("virtual address is in the page tables shared with the kernel")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 释放一个不再使用的页表（不能是根页表）
+    fn free_table(&mut self, ppn: PhysicalPageNumber) {
+        assert!(ppn != self.root_ppn, "cannot free the root page table");
+        self.page_tables.retain(|table| table.page_number() != ppn);
+        self.debug_assert_root_kept();
+    }
+
+    /// 让已经映射的虚拟页号改为指向另一个物理页号，保留原有的标志位，并刷新对应的 TLB
+    ///
+    /// 如果页面位于大页中，会先拆分大页
+    pub fn remap_one(
+        &mut self,
+        vpn: VirtualPageNumber,
+        ppn: PhysicalPageNumber,
+    ) -> MemoryResult<()> {
+        self.check_not_shared(vpn)?;
+        self.split_if_huge(vpn)?;
+        let entry = self
+            .find_existing_entry(vpn)
+            .filter(|entry| !entry.is_empty())
+            .ok_or("virtual address to remap is not mapped")?;
+        *entry = PageTableEntry::new(ppn, entry.flags());
+        self.flush(vpn);
+        debug_assert_eq!(self.validate_page(vpn), Ok(()));
+        Ok(())
+    }
+
+    /// 修改已经映射的虚拟页号的标志位，保留物理页号，并刷新对应的 TLB
+    ///
+    /// 如果页面位于大页中，会先拆分大页，只修改这一个页面
+    pub fn set_flags(&mut self, vpn: VirtualPageNumber, flags: Flags) -> MemoryResult<()> {
+        self.check_not_shared(vpn)?;
+        self.split_if_huge(vpn)?;
+        let entry = self
+            .find_existing_entry(vpn)
+            .filter(|entry| !entry.is_empty())
+            .ok_or("virtual address to set flags is not mapped")?;
+        *entry = PageTableEntry::new(entry.page_number(), flags);
+        self.flush(vpn);
+        debug_assert_eq!(self.validate_page(vpn), Ok(()));
+        Ok(())
+    }
+
+    /// 对区间内每个已经映射的页面，以当前的标志位调用 `predicate`，返回 `Some` 时改为新的标志位，返回修改的页面数
+    ///
+    /// 返回 `None` 或与当前相同的标志位时不修改，也不刷新 TLB；尚未映射的页面直接跳过。
+    /// 例如实现 W^X 时，对可写的页面返回去掉执行权限的标志位，对其余页面返回 `None`。
+    /// 所有修改在一个批次中完成，最后统一刷新 TLB。
+    pub fn set_flags_where(
+        &mut self,
+        page_range: Range<VirtualPageNumber>,
+        predicate: impl Fn(Flags) -> Option<Flags>,
+    ) -> MemoryResult<usize> {
+        let mut mapping = self.begin_batch();
+        let mut changed = 0;
+        for vpn in page_range.iter() {
+            let flags = match mapping.entry_flags(vpn) {
+                Some(flags) => flags,
+                None => continue,
+            };
+            match predicate(flags) {
+                Some(new_flags) if new_flags != flags => {
+                    mapping.set_flags(vpn, new_flags)?;
+                    changed += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(changed)
+    }
+
+    /// 记录所有叶子页表项的标志位
+    ///
+    /// 大页只记录一项，对应其中的第一个页面
+    pub fn snapshot_flags(&self) -> FlagsSnapshot {
+        let mut snapshot = FlagsSnapshot::default();
+        for step in self.walker() {
+            if step.level == self.leaf_level() || step.is_leaf() {
+                snapshot.flags.insert(step.vpn, step.entry.flags());
+            }
+        }
+        snapshot
+    }
+
+    /// 两个映射的低半部分是否映射了同一个物理帧，见 [`Mapping::shared_frames_with`]
+    pub fn shares_frames_with(&self, other: &Mapping) -> bool {
+        !self.shared_frames_with(other).is_empty()
+    }
+
+    /// 两个映射的低半部分都映射到的物理帧，按物理页号递增排列
+    ///
+    /// 高半部分是所有地址空间共用的内核，总是相同，因此不参与比较；大页中的每个帧都分别计入。
+    /// 例如 [`MemorySet::clone_into`] 复制内容之后结果为空，写时复制之后包含所有共用的页面，
+    /// 建立共享内存之后则恰好是共享的区域。
+    ///
+    /// [`MemorySet::clone_into`]: crate::memory::MemorySet::clone_into
+    pub fn shared_frames_with(&self, other: &Mapping) -> Vec<PhysicalPageNumber> {
+        let frames = self.lower_half_frames();
+        other
+            .lower_half_frames()
+            .into_iter()
+            .filter(|ppn| frames.contains(ppn))
+            .collect()
+    }
+
+    /// 低半部分的叶子页表项指向的所有物理帧
+    fn lower_half_frames(&self) -> BTreeSet<PhysicalPageNumber> {
+        let mut frames = BTreeSet::new();
+        for step in self.walker() {
+            if step.vpn >= VirtualPageNumber::floor(USER_ADDRESS_MAX) {
+                continue;
+            }
+            if step.level == self.leaf_level() || step.is_leaf() {
+                // 大页覆盖 512 ^ 剩余级数 个连续的帧
+                let count = 1 << (9 * (self.leaf_level() - step.level));
+                frames.extend(step.entry.page_number().iter_count(count));
+            }
+        }
+        frames
+    }
+
+    /// 检查两个映射的结构相同，即映射了相同的页面且标志位相同，否则 panic 并逐页列出差别
+    ///
+    /// 用于检查复制地址空间（例如 [`MemorySet::clone_into`]）的结果，不比较页面指向的物理帧。
+    /// 最多列出 32 个页面，其余的只给出数量。
+    ///
+    /// [`MemorySet::clone_into`]: crate::memory::MemorySet::clone_into
+    pub fn assert_structurally_eq(&self, other: &Mapping) {
+        let differences = self.snapshot_flags().diff(&other.snapshot_flags());
+        if differences.is_empty() {
+            return;
+        }
+        let mut report = String::new();
+        for (vpn, left, right) in differences.iter().take(32) {
+            let _ = match (left, right) {
+                (Some(flags), None) => writeln!(report, "  {}: only in left ({:?})", vpn, flags),
+                (None, Some(flags)) => writeln!(report, "  {}: only in right ({:?})", vpn, flags),
+                _ => writeln!(
+                    report,
+                    "  {}: flags {:?} != {:?}",
+                    vpn,
+                    left.unwrap(),
+                    right.unwrap()
+                ),
+            };
+        }
+        if differences.len() > 32 {
+            let _ = writeln!(report, "  ... {} more", differences.len() - 32);
+        }
+        panic!(
+            "mappings differ at {} pages:\n{}",
+            differences.len(),
+            report
+        );
+    }
+
+    /// 将标志位恢复为快照中的值，只修改并刷新发生了变化的页表项
+    ///
+    /// 访问位和修改位由硬件维护（修改位还用于写回文件映射），保留当前的值。
+    /// 快照之后新映射的页面不受影响；快照中的页面如果已经取消映射则返回 `Err`。
+    /// 快照和恢复之间不应拆分或合并大页，否则页表项与快照不再一一对应。
+    pub fn restore_flags(&mut self, snapshot: &FlagsSnapshot) -> MemoryResult<()> {
+        let hardware_flags = Flags::ACCESSED | Flags::DIRTY;
+        let mut mapping = self.begin_batch();
+        for (&vpn, &flags) in snapshot.flags.iter() {
+            let (entry, _level) = mapping
+                .find_existing_leaf(vpn)
+                .filter(|(entry, _level)| !entry.is_empty())
+                .ok_or("page in the flags snapshot is no longer mapped")?;
+            let restored = (flags - hardware_flags) | (entry.flags() & hardware_flags);
+            if restored != entry.flags() {
+                mapping.check_not_shared(vpn)?;
+                *entry = PageTableEntry::new(entry.page_number(), restored);
+                mapping.flush(vpn);
+            }
+        }
+        Ok(())
+    }
+
+    /// 刷新 TLB 中给定虚拟页号的项
+    ///
+    /// 先刷新本核，如果映射还在其他核上生效，再通知它们刷新。处于批次中时只记录页面。
+    ///
+    /// 映射没有被本核的 `satp` 使用时（例如为尚未运行的子进程建立映射），本核的 TLB 中不会有它的项：
+    /// 切换 `satp` 时总会刷新整个 TLB（见 [`Mapping::activate`]），此时刷新的反而是当前地址空间，因此跳过。
+    /// 高半部分的页表可能与正在使用的映射共用（见 [`Mapping::link_kernel_subtree`]），总是刷新
+    fn flush(&mut self, vpn: VirtualPageNumber) {
+        self.generation += 1;
+        if let Some(pages) = &mut self.batch {
+            pages.push(vpn);
+            return;
+        }
+        if vpn >= VirtualPageNumber::floor(USER_ADDRESS_MAX) || self.is_active() {
+            let va = VirtualAddress::from(vpn).0;
+            unsafe { llvm_asm!("sfence.vma $0" :: "r"(va) :: "volatile") };
+        }
+        if let Some(shootdown) = &self.shootdown {
+            if !self.remote_harts.is_empty() {
+                shootdown.remote_flush(&self.remote_harts, Some(vpn), None);
+            }
+        }
+    }
+
+    /// 刷新整个 TLB，同样会通知映射生效的其他核
+    fn flush_all(&self) {
+        unsafe { llvm_asm!("sfence.vma" :::: "volatile") };
+        if let Some(shootdown) = &self.shootdown {
+            if !self.remote_harts.is_empty() {
+                shootdown.remote_flush(&self.remote_harts, None, None);
+            }
+        }
+    }
+
+    /// 在设备通过 DMA 读取或写入 `page_range` 的前后调用，保证内存访问的顺序
+    ///
+    /// 我们假设 DMA 与 CPU 的缓存是一致的（QEMU 的 virt 平台即如此），因此只需要一条 `fence iorw, iorw`：
+    /// 交给设备之前，使 CPU 对这些页面的写入先于之后通知设备的 MMIO 完成；
+    /// 从设备取回之后，使设备的写入对 CPU 之后的读取可见。
+    /// 如果平台的 DMA 与缓存不一致，还需要逐个缓存行写回或作废（例如 Zicbom 扩展的 `cbo.flush`），目前没有实现。
+    pub fn fence_range(&self, page_range: Range<VirtualPageNumber>) {
+        debug_assert!(
+            page_range.iter().all(|vpn| self.is_mapped(vpn)),
+            "fence on pages that are not mapped"
+        );
+        unsafe { llvm_asm!("fence iorw, iorw" :::: "volatile") };
+    }
+
+    /// 通过每个页面的虚拟地址读取一次，使 `page_range` 的页表项预先进入 TLB
+    ///
+    /// 用于在进入对延迟敏感的代码之前预热 TLB。映射必须正被 `satp` 使用（见 [`Mapping::is_active`]），
+    /// 所有页面都必须可读；用户页面还需要 `sstatus.SUM` 允许内核访问。
+    /// 尚未分配的按需映射页面会在读取时触发缺页异常而被分配，效果与
+    /// [`MemorySet::populate`](crate::memory::MemorySet::populate) 相同。
+    /// 缺页处理需要获取当前进程的锁，因此调用时不能持有它，否则会死锁。
+    pub fn touch(&self, page_range: Range<VirtualPageNumber>) {
+        debug_assert!(self.is_active(), "touching pages of an inactive mapping");
+        for vpn in page_range.iter() {
+            let address = VirtualAddress::from(vpn);
+            unsafe { core::ptr::read_volatile(address.0 as *const u8) };
+        }
+    }
+
     /// 为给定的虚拟 / 物理页号建立映射关系
-    fn map_one(
+    pub(super) fn map_one(
         &mut self,
         vpn: VirtualPageNumber,
         ppn: PhysicalPageNumber,
         flags: Flags,
     ) -> MemoryResult<()> {
+        #[cfg(feature = "map_timing")]
+        let _timer = super::timing::Timer::start(super::timing::Timed::MapOne);
         // 定位到页表项
         let entry = self.find_entry(vpn)?;
         assert!(entry.is_empty(), "virtual address is already mapped");
         // 页表项为空，则写入内容
         *entry = PageTableEntry::new(ppn, flags);
+        debug_assert_eq!(self.validate_page(vpn), Ok(()));
+        Ok(())
+    }
+
+    /// 以倒数第二级的叶子页表项为 2M 对齐的虚拟 / 物理页号建立大页映射
+    ///
+    /// 虚拟页号和物理页号都必须 2M 对齐，且对应的区间中还没有任何映射
+    pub(super) fn map_huge_one(
+        &mut self,
+        vpn: VirtualPageNumber,
+        ppn: PhysicalPageNumber,
+        flags: Flags,
+    ) -> MemoryResult<()> {
+        const PAGES: usize = PAGE_SIZE / 8;
+        assert!(
+            vpn.0 % PAGES == 0 && ppn.0 % PAGES == 0,
+            "huge page is not aligned"
+        );
+        let huge_level = self.leaf_level() - 1;
+        let entry = self.find_entry_at(vpn, huge_level)?;
+        assert!(entry.is_empty(), "virtual address is already mapped");
+        *entry = PageTableEntry::new(ppn, flags);
+        debug_assert_eq!(self.validate_page(vpn), Ok(()));
+        Ok(())
+    }
+
+    /// 检查整个页表树的一致性，出错时返回第一个发现的问题
+    ///
+    /// - 指向下一级的页表项必须有效，且指向本映射记录的、级数正确的页表，每个页表只被指向一次
+    /// - 除根页表以外，页表不能为空（空的页表应当已被释放）
+    /// - 最后一级中有效的页表项必须是叶子
+    ///
+    /// 在 debug 模式下，每次修改页表项后都会用 [`Mapping::validate_page`] 检查经过的页表项
+    pub fn validate(&self) -> MemoryResult<()> {
+        let mut visited = Vec::new();
+        // 遍历器先给出指向下一级的页表项，再进入下一级，因此出错的页表项指向的页表不会被访问
+        for step in self.walker() {
+            self.validate_entry(step.entry, step.level)?;
+            if step.level < self.leaf_level() && step.entry.has_next_level() {
+                let next_ppn = step.entry.page_number();
+                if visited.contains(&next_ppn) {
+                    return Err("page table is referenced more than once");
+                }
+                visited.push(next_ppn);
+            }
+        }
+        if visited.len() + 1 != self.page_tables.len() {
+            return Err("page table is tracked but not reachable from root");
+        }
+        Ok(())
+    }
+
+    /// 检查没有同时可写和可执行的叶子页表项（W^X），出错时打印第一个这样的页面
+    ///
+    /// 检查的是单个页表项：通过 [`MemorySet::map_alias`] 让同一个帧在一个虚拟页面可写、
+    /// 在另一个虚拟页面可执行的做法（例如 JIT）不受影响。从 elf 建立映射之后会调用它，
+    /// 拒绝会产生可写又可执行页面的 elf 文件。
+    ///
+    /// [`MemorySet::map_alias`]: crate::memory::MemorySet::map_alias
+    pub fn validate_security(&self) -> MemoryResult<()> {
+        for step in self.walker() {
+            let is_leaf = step.level == self.leaf_level() || step.is_leaf();
+            if is_leaf
+                && step
+                    .entry
+                    .flags()
+                    .contains(Flags::WRITABLE | Flags::EXECUTABLE)
+            {
+                println!("page {} is both writable and executable", step.vpn);
+                return Err("page is both writable and executable");
+            }
+        }
+        Ok(())
+    }
+
+    /// 检查用户地址空间（[`USER_ADDRESS_MAX`] 以下）中的每个叶子页表项都带有 USER 位
+    ///
+    /// 用户程序的映射中，低半部分只有用户程序自己的字段。缺少 USER 位说明新建字段时漏掉了默认的标志，
+    /// 用户程序访问这些页面时会意外地触发缺页异常。从 elf 建立用户程序的映射之后，在 debug 模式下检查
+    pub fn validate_user_half(&self) -> MemoryResult<()> {
+        let user_end = VirtualPageNumber::ceil(USER_ADDRESS_MAX);
+        for step in self.walker() {
+            let is_leaf = step.level == self.leaf_level() || step.is_leaf();
+            if is_leaf && step.vpn < user_end && !step.entry.flags().contains(Flags::USER) {
+                return Err("page in the user half is not accessible to user");
+            }
+        }
+        Ok(())
+    }
+
+    /// 只检查页表的归属：每个指向下一级的页表项都必须指向本映射持有的页表
+    ///
+    /// 页表被释放后如果没有清除指向它的页表项，之后的访问会落到已经归还分配器、可能被另作他用的帧上。
+    /// 与 [`Mapping::validate`] 相比，这里不要求页表项有效，也不要求页表非空，
+    /// 因此在映射中途出错（例如分配页表失败）后留下的半成品状态中也可以使用；但对悬空的页表项更严格：
+    /// 即使没有 `VALID` 位，只要页表项看起来指向下一级，就必须指向持有的页表。
+    /// 此外要求根页表位于 `page_tables` 的首位，且没有重复持有的页表。
+    pub fn check_table_integrity(&self) -> MemoryResult<()> {
+        if self.page_tables.is_empty() {
+            return Ok(());
+        }
+        if self.page_tables[0].page_number() != self.root_ppn {
+            return Err("root page table is not the first tracked page table");
+        }
+        for (index, table) in self.page_tables.iter().enumerate() {
+            if self.page_tables[..index]
+                .iter()
+                .any(|other| other.page_number() == table.page_number())
+            {
+                return Err("page table is tracked more than once");
+            }
+        }
+        // 遍历器先给出页表项，再进入它指向的页表，因此悬空的页表项在进入之前就会被发现
+        for step in self.walker() {
+            if step.level == self.leaf_level() || !step.entry.has_next_level() {
+                continue;
+            }
+            let ppn = step.entry.page_number();
+            if !self
+                .page_tables
+                .iter()
+                .any(|table| table.page_number() == ppn && table.level() == step.level + 1)
+            {
+                return Err("page table entry points to a page table that is not held");
+            }
+        }
+        Ok(())
+    }
+
+    /// 只检查查找给定虚拟页号时经过的页表项，开销与映射的大小基本无关
+    pub fn validate_page(&self, vpn: VirtualPageNumber) -> MemoryResult<()> {
+        let mut table: &PageTable = PhysicalAddress::from(self.root_ppn).deref_kernel();
+        for (level, index) in vpn.levels(self.mode.levels()).iter().enumerate() {
+            let entry = &table.entries[*index];
+            if entry.is_empty() {
+                break;
+            }
+            self.validate_entry(entry, level)?;
+            if level == self.leaf_level() || !entry.has_next_level() {
+                break;
+            }
+            table = entry.get_next_table();
+        }
+        Ok(())
+    }
+
+    /// 检查第 `level` 级页表中的一个非空页表项
+    ///
+    /// 最后一级中无效的页表项不会被硬件使用，不做检查
+    fn validate_entry(&self, entry: &PageTableEntry, level: usize) -> MemoryResult<()> {
+        if !entry.has_next_level() {
+            return Ok(());
+        }
+        let valid = entry.flags().contains(Flags::VALID);
+        if level == self.leaf_level() {
+            return if valid {
+                Err("valid last level page table entry is not a leaf")
+            } else {
+                Ok(())
+            };
+        }
+        if !valid {
+            return Err("page table entry points to next level but is not valid");
+        }
+        let ppn = entry.page_number();
+        match self
+            .page_tables
+            .iter()
+            .find(|table| table.page_number() == ppn)
+        {
+            None => return Err("page table entry points to an untracked page table"),
+            Some(table) if table.level() != level + 1 => {
+                return Err("page table is recorded at the wrong level")
+            }
+            Some(_) => {}
+        }
+        if entry.get_next_table().is_empty() {
+            return Err("empty page table is not freed");
+        }
         Ok(())
     }
 }
@@ -4,17 +4,29 @@
 use crate::memory::{
     address::*,
     config::*,
-    frame::FrameTracker,
-    mapping::{Flags, MapType, Mapping, Segment},
+    frame::{FrameTracker, FRAME_ALLOCATOR},
+    mapping::{write_maps, AccessType, Backing, Flags, MapType, Mapping, Segment, OOM_KILLED},
     range::Range,
     MemoryResult,
 };
-use alloc::{vec, vec::Vec};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+use core::{cmp::Ordering, fmt, iter::once, mem::size_of};
+use lazy_static::*;
+use spin::Mutex;
 use xmas_elf::{
     program::{SegmentData, Type},
     ElfFile,
 };
 
+lazy_static! {
+    /// 每个核的陷入暂存帧，在第一次通过 [`MemorySet::map_trap_scratch`] 映射时分配，之后从不释放
+    static ref TRAP_SCRATCH_FRAMES: Mutex<Vec<Option<FrameTracker>>> =
+        Mutex::new((0..TRAP_SCRATCH_HARTS).map(|_| None).collect());
+}
+
+/// [`MemorySet`] 的 `Debug` 输出中最多列出的字段数
+const DEBUG_SEGMENT_LIMIT: usize = 32;
+
 /// 一个进程所有关于内存空间管理的信息
 pub struct MemorySet {
     /// 维护页表和映射关系
@@ -22,12 +34,52 @@ pub struct MemorySet {
     /// 每个字段
     pub segments: Vec<Segment>,
     /// 所有分配的物理页面映射信息
-    pub allocated_pairs: Vec<(VirtualPageNumber, FrameTracker)>,
+    ///
+    /// 物理页面可能被共享，只有在最后一个引用被移除时才会释放
+    pub allocated_pairs: Vec<(VirtualPageNumber, Arc<FrameTracker>)>,
+    /// 最多允许多少个页面由分配的物理帧支持，`None` 表示不限制，见 [`MemorySet::set_frame_limit`]
+    frame_limit: Option<usize>,
+}
+
+/// 输出字段数、驻留页面数、根页表和按地址排序的字段列表
+///
+/// 最多列出 [`DEBUG_SEGMENT_LIMIT`] 个字段，其余的只给出数量，避免在 panic 信息中输出过多内容
+impl fmt::Debug for MemorySet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "MemorySet {{ segments: {}, resident pages: {}, root: {} }}",
+            self.segments.len(),
+            self.resident_pages(),
+            self.mapping.root_ppn()
+        )?;
+        let segments = self.sorted_segments();
+        for segment in segments.iter().take(DEBUG_SEGMENT_LIMIT) {
+            writeln!(f, "    {}", segment)?;
+        }
+        if segments.len() > DEBUG_SEGMENT_LIMIT {
+            writeln!(f, "    ... {} more", segments.len() - DEBUG_SEGMENT_LIMIT)?;
+        }
+        Ok(())
+    }
+}
+
+/// 开启 `secure_wipe` feature 时，在帧回到分配器之前将其清零
+#[cfg(feature = "secure_wipe")]
+impl Drop for MemorySet {
+    fn drop(&mut self) {
+        self.secure_clear();
+    }
 }
 
 impl MemorySet {
     /// 创建内核重映射
+    ///
+    /// 目前只构建 Sv39 的内核页表：即使探测到硬件支持 Sv48（见 [`Mapping::paging_mode`]）也退回 Sv39，
+    /// 因为 [`USER_ADDRESS_MAX`]、[`KERNEL_ADDRESS_MIN`] 以及 [`VirtualAddress::is_user`]、[`Segment::in_correct_half`] 等对地址的划分都按照 Sv39 计算。
     pub fn new_kernel() -> MemoryResult<MemorySet> {
+        #[cfg(feature = "map_timing")]
+        let _timer = super::timing::Timer::start(super::timing::Timed::NewKernel);
         // 在 linker.ld 里面标记的各个字段的起始点，均为 4K 对齐
         extern "C" {
             fn text_start();
@@ -36,66 +88,248 @@ impl MemorySet {
             fn bss_start();
         }
 
-        // 建立字段
-        let segments = vec![
-            // DEVICE 段，rw-
-            Segment {
-                map_type: MapType::Linear,
-                range: Range::from(DEVICE_START_ADDRESS..DEVICE_END_ADDRESS),
-                flags: Flags::READABLE | Flags::WRITABLE,
-            },
-            // .text 段，r-x
-            Segment {
-                map_type: MapType::Linear,
-                range: Range::from((text_start as usize)..(rodata_start as usize)),
-                flags: Flags::READABLE | Flags::EXECUTABLE,
-            },
-            // .rodata 段，r--
-            Segment {
-                map_type: MapType::Linear,
-                range: Range::from((rodata_start as usize)..(data_start as usize)),
-                flags: Flags::READABLE,
-            },
-            // .data 段，rw-
-            Segment {
-                map_type: MapType::Linear,
-                range: Range::from((data_start as usize)..(bss_start as usize)),
-                flags: Flags::READABLE | Flags::WRITABLE,
-            },
-            // .bss 段，rw-
+        let mut memory_set = MemorySet {
+            mapping: Mapping::new()?,
+            segments: Vec::new(),
+            allocated_pairs: Vec::new(),
+            frame_limit: None,
+        };
+        // 内核的字段在每个地址空间中都相同，之后新建的内核字段都带有 GLOBAL
+        memory_set.mapping.set_default_flags(Flags::GLOBAL);
+        // 逐个字段映射，出错时能看出是哪个字段
+        // DEVICE 段，rw-
+        memory_set.map_named_linear(
+            "DEVICE",
+            Range::from(DEVICE_START_ADDRESS..DEVICE_END_ADDRESS),
+            Flags::RW,
+        )?;
+        // .text 段，r-x
+        memory_set.map_named_linear(
+            ".text",
+            Range::from((text_start as usize)..(rodata_start as usize)),
+            Flags::RX,
+        )?;
+        // .rodata 段，r--
+        memory_set.map_named_linear(
+            ".rodata",
+            Range::from((rodata_start as usize)..(data_start as usize)),
+            Flags::R,
+        )?;
+        // .data 段，rw-
+        memory_set.map_named_linear(
+            ".data",
+            Range::from((data_start as usize)..(bss_start as usize)),
+            Flags::RW,
+        )?;
+        // .bss 段，rw-
+        memory_set.map_named_linear(
+            ".bss",
+            Range::from(VirtualAddress::from(bss_start as usize)..*KERNEL_END_ADDRESS),
+            Flags::RW,
+        )?;
+        // 剩余内存空间，rw-
+        memory_set.map_named_linear(
+            "free memory",
+            Range::from(*KERNEL_END_ADDRESS..VirtualAddress::from(memory_end())),
+            Flags::RW,
+        )?;
+        // 中断处理需要访问 CLINT 和 PLIC，它们在任何地址空间中都要可见
+        memory_set.map_clint()?;
+        memory_set.map_plic()?;
+        Ok(memory_set)
+    }
+
+    /// 线性映射内核的一个字段，`name` 只用于出错时指明是哪个字段
+    ///
+    /// 错误只能是静态字符串，因此出错时先打印字段名和区间，例如
+    /// `failed mapping .bss [0xffffffff80220000-0xffffffff80a30000]: no available frame to allocate`，
+    /// 再原样返回错误。字段不能与内核动态映射区域（[`KERNEL_VMALLOC_BASE`] 到 [`KERNEL_VMALLOC_END`]）重叠，
+    /// debug 模式下会检查
+    fn map_named_linear(
+        &mut self,
+        name: &str,
+        range: Range<VirtualAddress>,
+        flags: Flags,
+    ) -> MemoryResult<()> {
+        let segment = Segment {
+            map_type: MapType::Linear,
+            range,
+            flags: flags | self.mapping.default_flags_for(range),
+        };
+        // 动态映射区域留给 alloc_anywhere，线性映射的地址都在 KERNEL_MAP_OFFSET 以上，不会与它重叠
+        debug_assert!(
+            !segment
+                .range
+                .overlap_with(&Range::from(KERNEL_VMALLOC_BASE..KERNEL_VMALLOC_END)),
+            "linear segment {} overlaps the vmalloc window",
+            name
+        );
+        let frames = self.mapping.map(&segment, None).map_err(|message| {
+            println!(
+                "failed mapping {} [{:#x}-{:#x}]: {}",
+                name, segment.range.start.0, segment.range.end.0, message
+            );
+            message
+        })?;
+        // 同时将新分配的映射关系保存到 allocated_pairs 中
+        self.allocated_pairs.extend(
+            frames
+                .into_iter()
+                .map(|(vpn, frame)| (vpn, Arc::new(frame))),
+        );
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// 从 MMIO 字段中的 `va` 处读取一个 32 位的设备寄存器
+    ///
+    /// `va` 必须 4 字节对齐，且位于可读的 MMIO 字段（见 [`Segment::is_mmio`]）中，否则返回 `Err`。
+    /// 地址经过本映射转换，再通过线性映射进行 volatile 访问，因此不会误读普通（可缓存的）内存。
+    pub fn mmio_read_u32(&self, va: VirtualAddress) -> MemoryResult<u32> {
+        let pa = self.mmio_physical_address(va, AccessType::Load)?;
+        Ok(unsafe { core::ptr::read_volatile(pa.linear_va().0 as *const u32) })
+    }
+
+    /// 向 MMIO 字段中的 `va` 处写入一个 32 位的设备寄存器
+    ///
+    /// 要求与 [`MemorySet::mmio_read_u32`] 相同，字段还必须可写
+    pub fn mmio_write_u32(&self, va: VirtualAddress, value: u32) -> MemoryResult<()> {
+        let pa = self.mmio_physical_address(va, AccessType::Store)?;
+        unsafe { core::ptr::write_volatile(pa.linear_va().0 as *mut u32, value) };
+        Ok(())
+    }
+
+    /// 检查 `va` 可以作为 32 位设备寄存器进行 `access` 访问，并得到其物理地址
+    fn mmio_physical_address(
+        &self,
+        va: VirtualAddress,
+        access: AccessType,
+    ) -> MemoryResult<PhysicalAddress> {
+        if va.0 % 4 != 0 {
+            return Err("MMIO register address is not aligned");
+        }
+        let vpn = VirtualPageNumber::floor(va);
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.page_range().contains(vpn))
+            .ok_or("MMIO register address is outside any segment")?;
+        if !segment.is_mmio() {
+            return Err("address is not in an MMIO segment");
+        }
+        if !segment.flags.contains(access.required_flag()) {
+            return Err("MMIO segment does not permit this access");
+        }
+        self.mapping
+            .translate(va)
+            .ok_or("MMIO register address is not mapped")
+    }
+
+    /// 限制这个地址空间最多有多少个页面由分配的物理帧支持，`None` 表示不限制
+    ///
+    /// 用于防止单个进程耗尽所有物理帧。分配帧的途径（映射字段、缺页、复制地址空间等）
+    /// 在超出限制时返回 `Err`，而不是等到物理帧真正耗尽。计数即 [`MemorySet::resident_pages`]，
+    /// 取消映射时自然减少；设置的限制低于当前的数量时，已有的页面不受影响，只是不能再分配。
+    /// 为共享的页面复制出单独的帧（写时复制、写入影子栈）虽然不改变页面数，但同样分配了新的帧，
+    /// 也按再分配一个页面检查，因此达到限制之后写入共享的页面会失败。
+    pub fn set_frame_limit(&mut self, limit: Option<usize>) {
+        self.frame_limit = limit;
+    }
+
+    /// 检查再分配 `count` 个页面是否会超出 [`MemorySet::set_frame_limit`] 设置的限制
+    fn check_frame_limit(&self, count: usize) -> MemoryResult<()> {
+        match self.frame_limit {
+            Some(limit) if self.resident_pages() + count > limit => Err("frame limit exceeded"),
+            _ => Ok(()),
+        }
+    }
+
+    /// 通过线性映射将一段设备的 MMIO 区域映射为内核可读写（`rw-`，全局）
+    ///
+    /// RISC-V 的页表项中没有描述缓存属性的位，设备区域不可缓存由平台的 PMA 保证
+    pub fn map_mmio(&mut self, range: Range<PhysicalAddress>) -> MemoryResult<()> {
+        self.add_segment(
             Segment {
                 map_type: MapType::Linear,
-                range: Range::from(VirtualAddress::from(bss_start as usize)..*KERNEL_END_ADDRESS),
-                flags: Flags::READABLE | Flags::WRITABLE,
+                range: Range::from(
+                    VirtualAddress::from(range.start)..VirtualAddress::from(range.end),
+                ),
+                flags: Flags::RW | Flags::GLOBAL,
             },
-            // 剩余内存空间，rw-
+            None,
+        )
+    }
+
+    /// 映射 CLINT 的 MMIO 区域
+    pub fn map_clint(&mut self) -> MemoryResult<()> {
+        self.map_mmio(Range::from(CLINT_START_ADDRESS..CLINT_END_ADDRESS))
+    }
+
+    /// 映射 PLIC 的 MMIO 区域
+    pub fn map_plic(&mut self) -> MemoryResult<()> {
+        self.map_mmio(Range::from(PLIC_START_ADDRESS..PLIC_END_ADDRESS))
+    }
+
+    /// 为第 `hart` 个核映射它的陷入暂存页（`rw-`，全局），返回其页号
+    ///
+    /// 暂存页位于 [`TRAP_SCRATCH_BASE`] 之上按核号排列的固定地址，每个地址空间都应映射。
+    /// 每个核只有一个暂存帧，在第一次映射时分配并由全局的表持有，之后不会释放；
+    /// 所有地址空间都把这个地址映射到同一个帧（见 [`MapType::Shared`]），
+    /// 这样陷入入口在栈还不可用时也能在这里保存寄存器，而不必关心之前运行的是哪个进程。
+    /// 同一虚拟地址在各个地址空间中的翻译相同，帧也始终有效，因此可以标记为全局，
+    /// 切换 `satp` 之后留在 TLB 中的项仍然正确。
+    /// 核号超出 [`TRAP_SCRATCH_HARTS`] 或者这一页已被映射时返回 `Err`
+    pub fn map_trap_scratch(&mut self, hart: usize) -> MemoryResult<VirtualPageNumber> {
+        if hart >= TRAP_SCRATCH_HARTS {
+            return Err("hart id has no trap scratch page");
+        }
+        let vpn = VirtualPageNumber::floor(TRAP_SCRATCH_BASE) + hart;
+        let page_range = Range::from(vpn..vpn + 1);
+        if self.overlap_with(page_range) {
+            return Err("trap scratch page is already mapped");
+        }
+        let ppn = {
+            let mut frames = TRAP_SCRATCH_FRAMES.lock();
+            match &frames[hart] {
+                Some(frame) => frame.page_number(),
+                None => {
+                    let frame = FRAME_ALLOCATOR.lock().alloc_zeroed()?;
+                    let ppn = frame.page_number();
+                    frames[hart] = Some(frame);
+                    ppn
+                }
+            }
+        };
+        self.add_segment(
             Segment {
-                map_type: MapType::Linear,
-                range: Range::from(*KERNEL_END_ADDRESS..VirtualAddress::from(MEMORY_END_ADDRESS)),
-                flags: Flags::READABLE | Flags::WRITABLE,
+                map_type: MapType::Shared(ppn),
+                range: page_range.into::<VirtualAddress>(),
+                flags: Flags::RW | Flags::GLOBAL,
             },
-        ];
-        let mut mapping = Mapping::new()?;
-        // 准备保存所有新分配的物理页面
-        let mut allocated_pairs = Vec::new();
+            None,
+        )?;
+        Ok(vpn)
+    }
 
-        // 每个字段在页表中进行映射
-        for segment in segments.iter() {
-            // 同时将新分配的映射关系保存到 allocated_pairs 中
-            allocated_pairs.extend(mapping.map(segment, None)?);
-        }
-        Ok(MemorySet {
-            mapping,
-            segments,
-            allocated_pairs,
-        })
+    /// 创建用户进程的内存映射，只包含内核重映射
+    ///
+    /// 在内核的默认标志位之上加上 `USER`，之后为用户程序新建的字段都带有 USER 位，内核的字段不受影响
+    pub fn new_user() -> MemoryResult<MemorySet> {
+        let mut memory_set = MemorySet::new_kernel()?;
+        let default_flags = memory_set.mapping.default_flags() | Flags::USER;
+        memory_set.mapping.set_default_flags(default_flags);
+        Ok(memory_set)
     }
 
     /// 通过 elf 文件创建内存映射（不包括栈）
     // todo: 有可能不同的字段出现在同一页？
     pub fn from_elf(file: &ElfFile, is_user: bool) -> MemoryResult<MemorySet> {
         // 建立带有内核映射的 MemorySet
-        let mut memory_set = MemorySet::new_kernel()?;
+        let mut memory_set = if is_user {
+            MemorySet::new_user()?
+        } else {
+            MemorySet::new_kernel()?
+        };
 
         // 遍历 elf 文件的所有部分
         for program_header in file.program_iter() {
@@ -125,6 +359,10 @@ impl MemorySet {
             // 建立映射并复制数据
             memory_set.add_segment(segment, Some(data))?;
         }
+        memory_set.mapping.validate_security()?;
+        if is_user {
+            debug_assert_eq!(memory_set.mapping.validate_user_half(), Ok(()));
+        }
 
         Ok(memory_set)
     }
@@ -136,13 +374,649 @@ impl MemorySet {
         self.mapping.activate();
     }
 
+    /// 临时激活页表并执行 `f`，结束后恢复原来的页表
+    pub fn with_active<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.mapping.with_active(f)
+    }
+
+    /// 内核初始化完成后，确保 .text 和 .rodata 段不可写
+    ///
+    /// 重新检查这两个段的字段和页表项，如果意外带有写权限则清除。检查的每个字段都会打印出来，并注明是否被修改。
+    /// 只应在启动完成后对内核映射调用一次。
+    pub fn lockdown(&mut self) {
+        extern "C" {
+            fn text_start();
+            fn data_start();
+        }
+        // .text 和 .rodata 在 linker.ld 中是连续的
+        let start = VirtualAddress::from(text_start as usize);
+        let end = VirtualAddress::from(data_start as usize);
+        for segment in self.segments.iter_mut() {
+            if segment.map_type != MapType::Linear
+                || segment.range.start < start
+                || segment.range.end > end
+            {
+                continue;
+            }
+            let mut writable = segment.flags.contains(Flags::WRITABLE);
+            segment.flags.remove(Flags::WRITABLE);
+            for vpn in segment.page_range().iter() {
+                // 只读取已有的页表项，不会为尚未映射的页面分配页表；没有映射的页面直接跳过
+                let flags = match self.mapping.entry_flags(vpn) {
+                    Some(flags) => flags,
+                    None => continue,
+                };
+                if flags.contains(Flags::WRITABLE) {
+                    writable = true;
+                    self.mapping
+                        .set_flags(vpn, flags - Flags::WRITABLE)
+                        .unwrap();
+                }
+            }
+            if writable {
+                println!("lockdown: removed write permission of {:x?}", segment);
+            } else {
+                println!("lockdown: {:x?} is read-only", segment);
+            }
+        }
+    }
+
+    /// 检查内核的页面满足 W^X：没有同时可写和可执行的页面，.rodata 段中也没有可写的页面
+    ///
+    /// 同时检查字段记录的权限和页表项中实际生效的权限，发现问题时打印出对应的页面并返回 `Err`。
+    /// 可以在 [`MemorySet::lockdown`] 之后由时钟中断定期调用，将被篡改的页表从无声的漏洞变为可以发现的错误。
+    pub fn assert_kernel_wx_invariant(&self) -> MemoryResult<()> {
+        extern "C" {
+            fn rodata_start();
+            fn data_start();
+        }
+        let rodata = Range::from(
+            VirtualAddress::from(rodata_start as usize)..VirtualAddress::from(data_start as usize),
+        )
+        .to_page_range();
+        let writable_and_executable = Flags::WRITABLE | Flags::EXECUTABLE;
+        for segment in self.segments.iter() {
+            if segment.flags.contains(Flags::USER) {
+                continue;
+            }
+            for vpn in segment.page_range().iter() {
+                let recorded = segment.flags_of(vpn);
+                let effective = self.mapping.entry_flags(vpn).unwrap_or_else(Flags::empty);
+                for flags in [recorded, effective].iter() {
+                    if flags.contains(writable_and_executable) {
+                        println!("W^X violation: {:x?} is writable and executable", vpn);
+                        return Err("kernel page is both writable and executable");
+                    }
+                    if rodata.contains(vpn) && flags.contains(Flags::WRITABLE) {
+                        println!("W^X violation: {:x?} in .rodata is writable", vpn);
+                        return Err("kernel read-only data is writable");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 从所有用户字段的权限中清除 `clear` 中的标志位，用于限制不可信的代码
+    ///
+    /// 例如加载完成后调用 `apply_flag_mask(Flags::EXECUTABLE | Flags::WRITABLE)`。
+    /// 页表项和字段记录的标志都会相应修改，并刷新 TLB。
+    /// 内核的字段不受影响，否则进入中断处理后内核自身就无法执行。
+    pub fn apply_flag_mask(&mut self, clear: Flags) -> MemoryResult<()> {
+        self.for_each_segment_mut(|segment| {
+            if !segment.flags.contains(Flags::USER) {
+                return;
+            }
+            segment.flags.remove(clear);
+            if let MapType::FramedWithFlags(page_flags) = &mut segment.map_type {
+                for flags in page_flags.iter_mut() {
+                    flags.remove(clear);
+                }
+            }
+        })
+    }
+
+    /// 对每个字段调用 `f` 修改其记录的权限，之后将变化同步到已经映射的页表项中
+    ///
+    /// `f` 只修改字段本身，不能访问页表；同步在之后统一进行，所有修改在一个批次中完成，最后统一刷新 TLB。
+    /// 同步时只改变字段中发生变化的标志位，页表项上的其他差别（例如写时复制去掉的写权限、访问位和修改位）保持不变。
+    /// 新增写权限时，如果页面的帧仍与其他页面共用，则不直接加上，而是留给缺页时的写时复制处理。
+    /// 尚未映射的页面直接跳过，之后缺页时会使用字段新的权限。
+    ///
+    /// `f` 不能改变字段的地址区间和映射类型（包括每页权限的数量），否则 panic。
+    pub fn for_each_segment_mut(&mut self, mut f: impl FnMut(&mut Segment)) -> MemoryResult<()> {
+        let mut mapping = self.mapping.begin_batch();
+        for segment in self.segments.iter_mut() {
+            let before = segment.clone();
+            f(segment);
+            let same_kind = match (&before.map_type, &segment.map_type) {
+                (MapType::FramedWithFlags(old), MapType::FramedWithFlags(new)) => {
+                    old.len() == new.len()
+                }
+                (old, new) => old == new,
+            };
+            assert!(
+                same_kind && segment.range == before.range,
+                "for_each_segment_mut cannot change the range or kind of a segment"
+            );
+            if *segment == before {
+                continue;
+            }
+            for vpn in segment.page_range().iter() {
+                let (old, new) = (before.flags_of(vpn), segment.flags_of(vpn));
+                if old == new {
+                    continue;
+                }
+                // 按需映射的字段中可能有尚未映射的页面，它们之后会使用字段记录的标志
+                let current = match mapping.entry_flags(vpn) {
+                    Some(flags) => flags,
+                    None => continue,
+                };
+                let mut added = new - old;
+                let shared = self
+                    .allocated_pairs
+                    .iter()
+                    .any(|(v, frame)| *v == vpn && Arc::strong_count(frame) > 1);
+                if shared {
+                    added.remove(Flags::WRITABLE);
+                }
+                mapping.set_flags(vpn, (current - (old - new)) | added)?;
+            }
+        }
+        Ok(())
+    }
+
     /// 添加一个 [`Segment`] 的内存映射
-    pub fn add_segment(&mut self, segment: Segment, init_data: Option<&[u8]>) -> MemoryResult<()> {
+    ///
+    /// 如果字段跨越了用户 / 内核地址空间的分界（见 [`Segment::in_correct_half`]），
+    /// 或者是包含 0 号页面的用户字段（见 [`Segment::maps_user_null_page`]），则返回 `Err`。
+    /// 字段的权限会加上映射的默认标志位（见 [`Mapping::set_default_flags`]）
+    pub fn add_segment(
+        &mut self,
+        mut segment: Segment,
+        init_data: Option<&[u8]>,
+    ) -> MemoryResult<()> {
+        #[cfg(feature = "map_timing")]
+        let _timer = super::timing::Timer::start(super::timing::Timed::AddSegment);
+        let default_flags = self.mapping.default_flags_for(segment.range);
+        segment.flags |= default_flags;
+        if let MapType::FramedWithFlags(page_flags) = &mut segment.map_type {
+            for flags in page_flags.iter_mut() {
+                *flags |= default_flags;
+            }
+        }
+        if !segment.in_correct_half() {
+            return Err("segment is in the wrong half of address space");
+        }
+        if segment.maps_user_null_page() {
+            return Err("user segment cannot map the null page");
+        }
         // 检测 segment 没有重合
         assert!(!self.overlap_with(segment.page_range()));
+        if matches!(
+            segment.map_type,
+            MapType::Framed | MapType::FramedWithFlags(_) | MapType::ShadowStack
+        ) {
+            self.check_frame_limit(segment.page_range().len())?;
+        }
         // 映射并将新分配的页面保存下来
-        self.allocated_pairs
-            .extend(self.mapping.map(&segment, init_data)?);
+        self.allocated_pairs.extend(
+            self.mapping
+                .map(&segment, init_data)?
+                .into_iter()
+                .map(|(vpn, frame)| (vpn, Arc::new(frame))),
+        );
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// 分配并映射一段页面，每个页面的权限标志由 `flags_fn` 给出
+    ///
+    /// 用于同一字段中不同页面权限不同的情况（例如 elf 字段的首页），
+    /// 避免将一个字段拆成多次映射。
+    pub fn map_alloc_with<F: Fn(VirtualPageNumber) -> Flags>(
+        &mut self,
+        page_range: Range<VirtualPageNumber>,
+        flags_fn: F,
+    ) -> MemoryResult<()> {
+        let page_flags: Vec<Flags> = page_range.iter().map(flags_fn).collect();
+        let flags = page_flags
+            .iter()
+            .fold(Flags::empty(), |union, flags| union | *flags);
+        self.add_segment(
+            Segment {
+                map_type: MapType::FramedWithFlags(page_flags),
+                range: page_range.into::<VirtualAddress>(),
+                flags,
+            },
+            None,
+        )
+    }
+
+    /// 在内核动态映射区域中找到 `count` 个连续的空闲页面，分配并映射，返回所用的页面区间
+    ///
+    /// 相当于内核中的 `vmalloc`：调用者不关心缓冲区放在哪里，只需要虚拟地址连续，
+    /// 物理帧则各自分配，不要求连续。只在 [`KERNEL_VMALLOC_BASE`] 和 [`KERNEL_VMALLOC_END`]
+    /// 之间寻找，不会与线性映射冲突。
+    pub fn alloc_anywhere(
+        &mut self,
+        count: usize,
+        flags: Flags,
+    ) -> MemoryResult<Range<VirtualPageNumber>> {
+        if count == 0 {
+            return Err("cannot allocate an empty range");
+        }
+        let window = Range::from(
+            VirtualPageNumber::floor(KERNEL_VMALLOC_BASE)
+                ..VirtualPageNumber::floor(KERNEL_VMALLOC_END),
+        );
+        // 按地址递增检查窗口内的字段，找到第一个足够长的空隙
+        let mut start = window.start;
+        for segment in self.sorted_segments() {
+            let used = match segment.page_range().intersect(&window) {
+                Some(used) => used,
+                None => continue,
+            };
+            if used.start >= start + count {
+                break;
+            }
+            start = start.max(used.end);
+        }
+        if start + count > window.end {
+            return Err("no free space in the kernel vmalloc area");
+        }
+        let page_range = Range::from(start..start + count);
+        self.add_segment(
+            Segment {
+                map_type: MapType::Framed,
+                range: page_range.into::<VirtualAddress>(),
+                flags,
+            },
+            None,
+        )?;
+        Ok(page_range)
+    }
+
+    /// 分配并映射一段页面，其中 2M 对齐的部分尽量使用物理上连续的 2M 大页
+    ///
+    /// 首尾不对齐的部分，以及无法分配连续 2M 物理内存的部分，仍然使用 4K 页面。
+    /// 大页可以减少页表占用的内存和 TLB 缺失，适合较大的缓冲区。
+    /// 每个 4K 页面仍然各自记录其帧，之后修改其中某个页面时会自动拆分大页。
+    pub fn map_alloc_huge(
+        &mut self,
+        page_range: Range<VirtualPageNumber>,
+        flags: Flags,
+    ) -> MemoryResult<()> {
+        let flags = flags | self.mapping.default_flags_for(page_range.into());
+        let segment = Segment {
+            map_type: MapType::Framed,
+            range: page_range.into::<VirtualAddress>(),
+            flags,
+        };
+        if !segment.in_correct_half() {
+            return Err("segment is in the wrong half of address space");
+        }
+        if segment.maps_user_null_page() {
+            return Err("user segment cannot map the null page");
+        }
+        // 检测 segment 没有重合
+        assert!(!self.overlap_with(page_range));
+        self.check_frame_limit(page_range.len())?;
+        let mapped = self.allocated_pairs.len();
+        if let Err(message) = self.map_alloc_huge_pages(page_range, flags) {
+            self.rollback_pairs(mapped);
+            return Err(message);
+        }
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// [`MemorySet::map_alloc_huge`] 中分配和映射页面的部分，出错时由调用者回滚
+    fn map_alloc_huge_pages(
+        &mut self,
+        page_range: Range<VirtualPageNumber>,
+        flags: Flags,
+    ) -> MemoryResult<()> {
+        const HUGE_PAGES: usize = PAGE_SIZE / 8;
+        let (head, middle, tail) = page_range.split_for_huge();
+        for vpn in head.iter() {
+            self.map_alloc_small(vpn, flags)?;
+        }
+        for vpn in middle.iter().step_by(HUGE_PAGES) {
+            match self.mapping.alloc_contiguous(HUGE_PAGES, HUGE_PAGES) {
+                Ok(mut frames) => {
+                    for frame in frames.iter_mut() {
+                        frame.fill(0);
+                    }
+                    self.mapping.map_huge_one(
+                        vpn,
+                        frames[0].page_number(),
+                        flags | Flags::VALID,
+                    )?;
+                    for (vpn, frame) in vpn.iter_count(HUGE_PAGES).zip(frames) {
+                        self.allocated_pairs.push((vpn, Arc::new(frame)));
+                    }
+                }
+                // 处理方式要求终止进程时不再退回
+                Err(OOM_KILLED) => return Err(OOM_KILLED),
+                // 没有连续的 2M 物理内存，这一段退回到 4K 页面
+                Err(_) => {
+                    for vpn in vpn.iter_count(HUGE_PAGES) {
+                        self.map_alloc_small(vpn, flags)?;
+                    }
+                }
+            }
+        }
+        for vpn in tail.iter() {
+            self.map_alloc_small(vpn, flags)?;
+        }
+        Ok(())
+    }
+
+    /// 撤销 `allocated_pairs` 中从 `from` 开始的页面的映射并释放它们的帧，用于映射字段中途失败时回滚
+    ///
+    /// 这些页面都是刚刚映射、还没有被共享的，大页被整个移除，不需要再分配页表
+    fn rollback_pairs(&mut self, from: usize) {
+        for (vpn, _frame) in self.allocated_pairs.split_off(from) {
+            // 大页中其余的页面在移除第一个页面时已经一并移除
+            self.mapping
+                .unmap_leaf(vpn)
+                .expect("failed to roll back a page that was just mapped");
+        }
+    }
+
+    /// 为一个 4K 页面分配清零的帧并映射，只用于已经检查过的字段
+    fn map_alloc_small(&mut self, vpn: VirtualPageNumber, flags: Flags) -> MemoryResult<()> {
+        let frame = self.mapping.alloc_frame(1)?;
+        self.mapping
+            .map_one(vpn, frame.page_number(), flags | Flags::VALID)?;
+        self.allocated_pairs.push((vpn, Arc::new(frame)));
+        Ok(())
+    }
+
+    /// 移除恰好覆盖 `page_range` 的按帧映射字段，返回它的帧而不是释放它们
+    ///
+    /// 返回的帧按虚拟页号排列，内容没有清零，可以交给 [`MemorySet::map_with_frames`] 重新使用，
+    /// 例如 `exec` 时新旧程序需要同样大小的区域，避免先释放再分配。
+    /// 如果其中有与其他页面或地址空间共用的帧（例如写时复制），则不做任何修改并返回 `Err`。
+    pub fn recycle_framed(
+        &mut self,
+        page_range: Range<VirtualPageNumber>,
+    ) -> MemoryResult<Vec<FrameTracker>> {
+        let index = self
+            .segments
+            .iter()
+            .position(|segment| {
+                segment.map_type == MapType::Framed && segment.page_range() == page_range
+            })
+            .ok_or("no framed segment covers exactly the range to recycle")?;
+        if self
+            .allocated_pairs
+            .iter()
+            .any(|(vpn, frame)| page_range.contains(*vpn) && Arc::strong_count(frame) > 1)
+        {
+            return Err("frame to recycle is shared with other pages");
+        }
+        let segment = self.segments.remove(index);
+        self.mapping.unmap(&segment)?;
+        let mut recycled: Vec<(VirtualPageNumber, FrameTracker)> =
+            Vec::with_capacity(page_range.len());
+        let mut kept =
+            Vec::with_capacity(self.allocated_pairs.len().saturating_sub(page_range.len()));
+        for (vpn, frame) in self.allocated_pairs.drain(..) {
+            if page_range.contains(vpn) {
+                // 前面已经检查过没有其他引用
+                let frame = Arc::try_unwrap(frame)
+                    .ok()
+                    .expect("frame to recycle is still shared");
+                recycled.push((vpn, frame));
+            } else {
+                kept.push((vpn, frame));
+            }
+        }
+        self.allocated_pairs = kept;
+        recycled.sort_unstable_by_key(|(vpn, _frame)| *vpn);
+        Ok(recycled.into_iter().map(|(_vpn, frame)| frame).collect())
+    }
+
+    /// 分配并映射一段页面，优先使用 `pool` 中的帧，不够时再从分配器分配
+    ///
+    /// 从 `pool` 的末尾取用帧，并在映射前清零，因此映射后的页面总是全 0，与其他分配方式一致。
+    /// 用不完的帧留在 `pool` 中，由调用者继续使用或 drop 释放。
+    /// 中途出错时撤销已经映射的页面，其中用掉的帧直接释放，不会放回 `pool`。
+    pub fn map_with_frames(
+        &mut self,
+        page_range: Range<VirtualPageNumber>,
+        flags: Flags,
+        pool: &mut Vec<FrameTracker>,
+    ) -> MemoryResult<()> {
+        let flags = flags | self.mapping.default_flags_for(page_range.into());
+        let segment = Segment {
+            map_type: MapType::Framed,
+            range: page_range.into::<VirtualAddress>(),
+            flags,
+        };
+        if !segment.in_correct_half() {
+            return Err("segment is in the wrong half of address space");
+        }
+        if segment.maps_user_null_page() {
+            return Err("user segment cannot map the null page");
+        }
+        // 检测 segment 没有重合
+        assert!(!self.overlap_with(page_range));
+        self.check_frame_limit(page_range.len())?;
+        let mapped = self.allocated_pairs.len();
+        for vpn in page_range.iter() {
+            let frame = match pool.pop() {
+                Some(mut frame) => {
+                    frame.fill(0);
+                    Ok(frame)
+                }
+                None => self.mapping.alloc_frame(1),
+            };
+            if let Err(message) = frame.and_then(|frame| {
+                self.mapping
+                    .map_one(vpn, frame.page_number(), flags | Flags::VALID)?;
+                self.allocated_pairs.push((vpn, Arc::new(frame)));
+                Ok(())
+            }) {
+                self.rollback_pairs(mapped);
+                return Err(message);
+            }
+        }
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// 预留一段页面，在第一次访问时才分配清零的物理帧
+    ///
+    /// 只记录字段本身，不分配任何物理帧，也不创建任何页表，
+    /// 因此预留很大的稀疏区间（例如 1GiB）的开销与区间大小无关。
+    pub fn map_lazy(
+        &mut self,
+        page_range: Range<VirtualPageNumber>,
+        flags: Flags,
+    ) -> MemoryResult<()> {
+        self.add_segment(
+            Segment {
+                map_type: MapType::Lazy,
+                range: page_range.into::<VirtualAddress>(),
+                flags,
+            },
+            None,
+        )
+    }
+
+    /// 将从 `ppn_start` 开始的一段设备 ROM 以只读方式映射到 `page_range`
+    ///
+    /// 页面只有 `R` 权限，不可写也不可执行。RISC-V 的页表项中没有描述缓存属性的位，
+    /// 是否可缓存由平台对这段物理地址的 PMA 决定，ROM 所在的区域通常是可缓存的；
+    /// 这里只保证映射本身不会引入写或执行的权限。
+    pub fn map_rom(
+        &mut self,
+        page_range: Range<VirtualPageNumber>,
+        ppn_start: PhysicalPageNumber,
+    ) -> MemoryResult<()> {
+        self.add_segment(
+            Segment {
+                map_type: MapType::Rom(ppn_start),
+                range: page_range.into::<VirtualAddress>(),
+                flags: Flags::READABLE,
+            },
+            None,
+        )
+    }
+
+    /// 在 `top` 之下分配 `pages` 个页面作为用户的影子栈，返回其页面区间
+    ///
+    /// 即使硬件不支持影子栈，也可以由内核模拟：页面对用户只读，用户直接写入时会被拒绝，
+    /// 压栈只能通过 [`MemorySet::shadow_stack_write`] 进行，出栈则是普通的读取。
+    /// 影子栈下方的一页必须没有映射，作为溢出时触发缺页异常的保护页。
+    pub fn map_shadow_stack(
+        &mut self,
+        top: VirtualPageNumber,
+        pages: usize,
+    ) -> MemoryResult<Range<VirtualPageNumber>> {
+        if pages == 0 || top.0 <= pages {
+            return Err("shadow stack does not fit below the given top");
+        }
+        let page_range = Range::from(top - pages..top);
+        let guard_page = page_range.start - 1;
+        if self.overlap_with(Range::from(guard_page..page_range.start)) {
+            return Err("guard page below the shadow stack is already mapped");
+        }
+        self.add_segment(
+            Segment {
+                map_type: MapType::ShadowStack,
+                range: page_range.into::<VirtualAddress>(),
+                flags: Flags::R | Flags::USER,
+            },
+            None,
+        )?;
+        Ok(page_range)
+    }
+
+    /// 在 `stack_top` 之下分配用户栈，按 System V 的约定放入参数和环境变量，返回初始的栈指针
+    ///
+    /// 从栈指针开始依次为 `argc`、以空指针结尾的 `argv` 和 `envp` 指针数组，
+    /// 以及只有 `AT_NULL` 一项的辅助向量；字符串本身以 `\0` 结尾放在栈顶。
+    /// 栈指针按 16 字节对齐。映射的页数恰好能放下这些内容，栈需要更大时应另外映射其下的页面
+    pub fn setup_user_stack(
+        &mut self,
+        stack_top: VirtualPageNumber,
+        args: &[&str],
+        envs: &[&str],
+    ) -> MemoryResult<VirtualAddress> {
+        let word = size_of::<usize>();
+        let top = VirtualAddress::from(stack_top);
+        let strings_size: usize = args.iter().chain(envs).map(|s| s.len() + 1).sum();
+        // argc、两个指针数组及其结尾的空指针，以及辅助向量中 AT_NULL 的类型和值
+        let table_size = (1 + args.len() + 1 + envs.len() + 1 + 2) * word;
+        let sp = top
+            .0
+            .checked_sub(strings_size + table_size)
+            .map(|sp| VirtualAddress(sp & !0xf))
+            .ok_or("arguments do not fit below the stack top")?;
+        let base = VirtualAddress::from(VirtualPageNumber::floor(sp));
+
+        // 在缓冲区中排好整个栈的内容，映射时一起写入
+        let mut data = vec![0u8; top - base];
+        let mut string_address = top - strings_size;
+        let mut pointers = Vec::with_capacity(args.len() + envs.len());
+        for string in args.iter().chain(envs) {
+            let offset = string_address - base;
+            data[offset..offset + string.len()].copy_from_slice(string.as_bytes());
+            pointers.push(string_address.0);
+            string_address += string.len() + 1;
+        }
+        let (argv, envp) = pointers.split_at(args.len());
+        let table = once(args.len())
+            .chain(argv.iter().copied())
+            .chain(once(0))
+            .chain(envp.iter().copied())
+            .chain(once(0))
+            .chain(once(0))
+            .chain(once(0));
+        for (i, value) in table.enumerate() {
+            let offset = sp - base + i * word;
+            data[offset..offset + word].copy_from_slice(&value.to_ne_bytes());
+        }
+
+        self.add_segment(
+            Segment {
+                map_type: MapType::Framed,
+                range: Range::from(base..top),
+                flags: Flags::URW,
+            },
+            Some(&data),
+        )?;
+        Ok(sp)
+    }
+
+    /// 向影子栈的 `va` 处写入一个值，这是写入影子栈的唯一途径
+    ///
+    /// 通过内核的线性映射写入，不受页表项权限的限制。`va` 必须按 `usize` 对齐。
+    /// 如果页面在复制地址空间时被共享，先为其复制出单独的帧，使写入不影响另一方。
+    pub fn shadow_stack_write(&mut self, va: VirtualAddress, value: usize) -> MemoryResult<()> {
+        let vpn = VirtualPageNumber::floor(va);
+        // 不在任何字段中的地址同样不在影子栈中，不使用 find_segment 针对缺页的错误
+        match self.find_segment(vpn) {
+            Ok(segment) if segment.map_type == MapType::ShadowStack => {}
+            _ => return Err("address is not in a shadow stack"),
+        }
+        if va.0 % core::mem::size_of::<usize>() != 0 {
+            return Err("shadow stack entry is not aligned");
+        }
+        let index = self
+            .allocated_pairs
+            .iter()
+            .position(|(v, _)| *v == vpn)
+            .ok_or("shadow stack page is not backed by an allocated frame")?;
+        if Arc::strong_count(&self.allocated_pairs[index].1) > 1 {
+            self.check_frame_limit(1)?;
+            let frame = self.mapping.alloc_frame_uninit(1)?;
+            self.migrate_page(vpn, frame, true)?;
+        }
+        let address =
+            PhysicalAddress::from(self.allocated_pairs[index].1.page_number()) + va.page_offset();
+        *address.deref_kernel::<usize>() = value;
+        Ok(())
+    }
+
+    /// 将 `existing_vpn` 所用的物理帧以另一组权限再映射到 `alias_vpn`
+    ///
+    /// 例如将同一个帧分别映射为 r-x 和 rw-，以实现 W^X。
+    /// 两个页面共用同一个 `Arc<FrameTracker>`，帧会在两者都被移除后才释放。
+    pub fn map_alias(
+        &mut self,
+        existing_vpn: VirtualPageNumber,
+        alias_vpn: VirtualPageNumber,
+        flags: Flags,
+    ) -> MemoryResult<()> {
+        let frame = self
+            .allocated_pairs
+            .iter()
+            .find(|(vpn, _)| *vpn == existing_vpn)
+            .map(|(_, frame)| frame.clone())
+            .ok_or("page to alias is not backed by an allocated frame")?;
+        let range = Range::<VirtualPageNumber>::from(alias_vpn..alias_vpn + 1).into();
+        let flags = flags | self.mapping.default_flags_for(range);
+        let segment = Segment {
+            map_type: MapType::Alias,
+            range,
+            flags,
+        };
+        if !segment.in_correct_half() {
+            return Err("segment is in the wrong half of address space");
+        }
+        if segment.maps_user_null_page() {
+            return Err("user segment cannot map the null page");
+        }
+        // 检测 segment 没有重合
+        assert!(!self.overlap_with(segment.page_range()));
+        self.mapping
+            .map_one(alias_vpn, frame.page_number(), flags | Flags::VALID)?;
+        self.allocated_pairs.push((alias_vpn, frame));
         self.segments.push(segment);
         Ok(())
     }
@@ -158,14 +1032,594 @@ impl MemorySet {
             .position(|s| s == segment)
             .expect("segment to remove cannot be found");
         self.segments.remove(segment_index);
+        // 写回文件映射中被修改过的页面
+        self.write_back(segment)?;
         // 移除映射
-        self.mapping.unmap(segment);
+        self.mapping.unmap(segment)?;
         // 释放页面（仅保留不属于 segment 的 vpn 和 frame）
         self.allocated_pairs
             .retain(|(vpn, _frame)| !segment.page_range().contains(*vpn));
         Ok(())
     }
 
+    /// 合并首尾相接、映射类型和权限都相同的字段，并将字段按起始地址排序
+    ///
+    /// 多次部分移除或修改权限之后，字段可能被拆得很碎，合并后查找字段更快，
+    /// [`MemorySet::format_maps`] 的输出也更紧凑。只改变字段的记录，不修改页表，也不移动任何帧。
+    ///
+    /// 只在页面边界处合并，不同映射类型或权限的字段不会合并。ROM 和文件映射还要求物理页号或文件偏移连续。
+    /// 合并后，之前保存的字段不再与记录中的一致，不能再用于 [`MemorySet::remove_segment`]；
+    /// 例如 [`AddressSpace`](crate::memory::AddressSpace) 记录了堆的各个字段，不应合并它的字段。
+    pub fn coalesce(&mut self) {
+        self.segments
+            .sort_unstable_by_key(|segment| segment.range.start);
+        let mut merged: Vec<Segment> = Vec::with_capacity(self.segments.len());
+        for segment in self.segments.drain(..) {
+            if let Some(last) = merged.last_mut() {
+                if let Some(map_type) = last.merged_type(&segment) {
+                    last.map_type = map_type;
+                    last.range.end = segment.range.end;
+                    continue;
+                }
+            }
+            merged.push(segment);
+        }
+        self.segments = merged;
+    }
+
+    /// 移除所有映射类型满足 `is_kind` 的字段，返回因此释放的物理帧数
+    ///
+    /// 例如 `free_segments_of_kind(|kind| matches!(kind, MapType::Lazy))` 移除所有按需映射的字段，
+    /// 用于整体拆除某个子系统建立的大量字段。与其他页面或地址空间共用的帧只会减少引用，
+    /// 在最后一个引用被移除时才释放，也不计入返回值；释放的页表同样不计入。
+    /// 所有页面在一次批量刷新 TLB 之后才释放其帧。
+    pub fn free_segments_of_kind(
+        &mut self,
+        is_kind: impl Fn(&MapType) -> bool,
+    ) -> MemoryResult<usize> {
+        let segments: Vec<Segment> = self
+            .segments
+            .iter()
+            .filter(|segment| is_kind(&segment.map_type))
+            .cloned()
+            .collect();
+        for segment in segments.iter() {
+            self.write_back(segment)?;
+        }
+        let mut mapping = self.mapping.begin_batch();
+        for segment in segments.iter() {
+            mapping.unmap(segment)?;
+        }
+        drop(mapping);
+        self.segments.retain(|segment| !is_kind(&segment.map_type));
+        let (removed, kept): (Vec<_>, Vec<_>) = core::mem::take(&mut self.allocated_pairs)
+            .into_iter()
+            .partition(|(vpn, _frame)| {
+                segments
+                    .iter()
+                    .any(|segment| segment.page_range().contains(*vpn))
+            });
+        self.allocated_pairs = kept;
+        // 这些帧会在 removed 被 drop 时释放
+        Ok(Self::last_references(&removed).len())
+    }
+
+    /// `pairs` 持有其全部引用的帧，即这些页面被移除后就会释放的帧
+    fn last_references(
+        pairs: &[(VirtualPageNumber, Arc<FrameTracker>)],
+    ) -> Vec<PhysicalPageNumber> {
+        let mut references: BTreeMap<PhysicalPageNumber, (usize, usize)> = BTreeMap::new();
+        for (_vpn, frame) in pairs.iter() {
+            let (count, strong_count) = references.entry(frame.page_number()).or_default();
+            *count += 1;
+            *strong_count = Arc::strong_count(frame);
+        }
+        references
+            .into_iter()
+            .filter(|(_ppn, (count, strong_count))| count == strong_count)
+            .map(|(ppn, _)| ppn)
+            .collect()
+    }
+
+    /// 将这个地址空间独占的所有帧清零，使其中的数据不会残留到之后分配出的帧中
+    ///
+    /// 与其他地址空间共用的帧（例如写时复制）不会被清零，由持有最后一个引用的地址空间负责。
+    /// 页面仍然保持映射，内容变为 0。开启 `secure_wipe` feature 时，drop 时会自动调用。
+    pub fn secure_clear(&mut self) {
+        for ppn in Self::last_references(&self.allocated_pairs) {
+            ppn.deref_kernel().fill(0);
+        }
+    }
+
+    /// 处理缺页异常，为按需映射的字段分配页面
+    ///
+    /// [`MapType::Lazy`] 的页面保持为 0，[`MapType::FileBacked`] 的页面从文件读入。
+    /// 如果 `va` 不属于任何按需映射的字段，字段的权限不允许 `access` 这种访问，
+    /// 或者对应页面已经映射（即访问权限不符），则返回 `Err`，此时应当终止线程。
+    /// `from_user` 表示异常是否来自用户态，此时访问内核地址（见 [`VirtualAddress::is_user`]）直接返回 `Err`
+    pub fn handle_page_fault(
+        &mut self,
+        va: VirtualAddress,
+        access: AccessType,
+        from_user: bool,
+    ) -> MemoryResult<()> {
+        // 用户态访问内核地址一定是越界，不必查找字段和页表
+        if from_user && !va.is_user() {
+            return Err("page fault at a kernel address from user mode");
+        }
+        if !va.is_user() && !va.is_kernel() {
+            return Err("page fault at an address in neither half of address space");
+        }
+        let vpn = VirtualPageNumber::floor(va);
+        let segment = self.find_segment(vpn)?;
+        if !segment.flags_of(vpn).contains(access.required_flag()) {
+            return Err("page fault with an access not permitted by the segment");
+        }
+        // 叶子可能是大页，因此按实际生效的标志位判断页面是否已经映射
+        if let Some(flags) = self.mapping.effective_flags(va) {
+            if access == AccessType::Store {
+                return self.copy_on_write(vpn, flags);
+            }
+        }
+        self.fault_in(vpn)
+    }
+
+    /// 写时复制：字段允许写入，但页表项的写权限在共享时被去掉了（见 [`MemorySet::clone_into`]）
+    ///
+    /// 如果帧仍被其他页面共用，先复制出一个单独的帧，然后恢复写权限
+    ///
+    /// `flags` 为页面当前生效的标志位
+    fn copy_on_write(&mut self, vpn: VirtualPageNumber, flags: Flags) -> MemoryResult<()> {
+        if flags.contains(Flags::WRITABLE) {
+            return Err("page fault at a mapped page, access is not permitted");
+        }
+        let shared = self
+            .allocated_pairs
+            .iter()
+            .find(|(v, _)| *v == vpn)
+            .map(|(_, frame)| Arc::strong_count(frame) > 1)
+            .ok_or("write to a read-only page that is not backed by an allocated frame")?;
+        if shared {
+            self.check_frame_limit(1)?;
+            let frame = self.mapping.alloc_frame_uninit(1)?;
+            self.migrate_page(vpn, frame, true)?;
+        }
+        self.mapping.set_flags(vpn, flags | Flags::WRITABLE)
+    }
+
+    /// 找到包含给定页面的字段
+    fn find_segment(&self, vpn: VirtualPageNumber) -> MemoryResult<&Segment> {
+        self.segments
+            .iter()
+            .find(|segment| segment.page_range().contains(vpn))
+            .ok_or("page fault at an address outside any segment")
+    }
+
+    /// 为按需映射的字段中尚未映射的页面分配并填充物理帧
+    fn fault_in(&mut self, vpn: VirtualPageNumber) -> MemoryResult<()> {
+        let segment = self.find_segment(vpn)?;
+        let backing = match segment.backing() {
+            Some(Backing::Anonymous) | None => {
+                return Err("page fault in a segment that is not lazily mapped")
+            }
+            Some(Backing::Swap) => return Err("swapped out pages are not supported"),
+            Some(backing) => backing,
+        };
+        if self.mapping.is_mapped(vpn) {
+            return Err("page fault at a mapped page, access is not permitted");
+        }
+        self.check_frame_limit(1)?;
+        // 分配清零的页面，文件映射再从文件中读入内容
+        let mut frame = self.mapping.alloc_frame(1)?;
+        if let Backing::File(file_backing) = &backing {
+            file_backing
+                .file
+                .read_page(file_backing.offset_of(segment, vpn), &mut frame[..])?;
+        }
+        let flags = segment.flags | Flags::VALID;
+        self.mapping.map_one(vpn, frame.page_number(), flags)?;
+        self.allocated_pairs.push((vpn, Arc::new(frame)));
+        Ok(())
+    }
+
+    /// 立即分配并填充一段按需映射的页面，类似 `mlock` 或 `MAP_POPULATE`
+    ///
+    /// 对范围内每个尚未映射的页面做与 [`MemorySet::handle_page_fault`] 相同的处理，之后访问这些页面不会再缺页。
+    /// 如果中途失败（例如物理帧不足），本次建立的映射会全部撤销，然后返回 `Err`。
+    pub fn populate(&mut self, page_range: Range<VirtualPageNumber>) -> MemoryResult<()> {
+        let mut populated = Vec::new();
+        for vpn in page_range.iter() {
+            if self.mapping.is_mapped(vpn) {
+                continue;
+            }
+            if let Err(message) = self.fault_in(vpn) {
+                // 回滚：撤销映射并释放本次分配的页面
+                for vpn in populated.iter() {
+                    self.mapping.unmap_one(*vpn)?;
+                }
+                self.allocated_pairs
+                    .retain(|(vpn, _frame)| !populated.contains(vpn));
+                return Err(message);
+            }
+            populated.push(vpn);
+        }
+        Ok(())
+    }
+
+    /// 将文件映射的字段中被修改过（DIRTY）的页面写回文件
+    ///
+    /// 对于其他类型的字段什么也不做。写回后清除页表项中的 DIRTY 位。
+    pub fn write_back(&mut self, segment: &Segment) -> MemoryResult<()> {
+        let backing = match &segment.map_type {
+            MapType::FileBacked(backing) => backing,
+            _ => return Ok(()),
+        };
+        for (vpn, frame) in self.allocated_pairs.iter() {
+            if !segment.page_range().contains(*vpn) {
+                continue;
+            }
+            let flags = self.mapping.entry_flags(*vpn).ok_or("page is not mapped")?;
+            if flags.contains(Flags::DIRTY) {
+                backing
+                    .file
+                    .write_page(backing.offset_of(segment, *vpn), &frame[..])?;
+                self.mapping.set_flags(*vpn, flags - Flags::DIRTY)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 将所有字段复制到 `dest` 中，`dest` 中已有的相同字段（例如内核的字段）会被跳过
+    ///
+    /// 线性映射和 ROM 的字段在 `dest` 中重新映射。对于分配了物理帧的页面：
+    /// - `share` 为 `false` 时，为 `dest` 分配新的帧并复制内容；
+    /// - `share` 为 `true` 时，两边共用同一个帧并同时去掉写权限，之后任何一方写入时，
+    ///   [`MemorySet::handle_page_fault`] 会为其复制出单独的帧（写时复制）。
+    ///
+    /// 因为写时复制需要修改当前的页表项，这里需要 `&mut self`。
+    /// 被 [`MapType::Alias`] 共用的帧总是被复制，否则写时复制之后别名和原页面就不再指向同一个帧。
+    /// 尚未分配的按需映射页面在两边都保持未分配。
+    pub fn clone_into(&mut self, dest: &mut MemorySet, share: bool) -> MemoryResult<()> {
+        let aliased = self.aliased_frames();
+        let segments: Vec<Segment> = self
+            .segments
+            .iter()
+            .filter(|segment| !dest.segments.contains(segment))
+            .cloned()
+            .collect();
+        // 先复制别名以外的字段，别名需要其原页面已经在 dest 中映射
+        let mut mapping = self.mapping.begin_batch();
+        for segment in segments.iter().filter(|s| s.map_type != MapType::Alias) {
+            if matches!(
+                segment.map_type,
+                MapType::Linear | MapType::Rom(_) | MapType::Shared(_)
+            ) {
+                dest.add_segment(segment.clone(), None)?;
+                continue;
+            }
+            assert!(!dest.overlap_with(segment.page_range()));
+            for (vpn, frame) in self.allocated_pairs.iter() {
+                if !segment.page_range().contains(*vpn) {
+                    continue;
+                }
+                let flags = mapping.entry_flags(*vpn).ok_or("page is not mapped")?;
+                dest.check_frame_limit(1)?;
+                if share && !aliased.contains(&frame.page_number()) {
+                    mapping.set_flags(*vpn, flags - Flags::WRITABLE)?;
+                    dest.mapping
+                        .map_one(*vpn, frame.page_number(), flags - Flags::WRITABLE)?;
+                    dest.allocated_pairs.push((*vpn, frame.clone()));
+                } else {
+                    let mut new_frame = dest.mapping.alloc_frame_uninit(1)?;
+                    frame.clone_contents_into(&mut new_frame);
+                    dest.mapping.map_one(*vpn, new_frame.page_number(), flags)?;
+                    dest.allocated_pairs.push((*vpn, Arc::new(new_frame)));
+                }
+            }
+            dest.segments.push(segment.clone());
+        }
+        // 结束批次，刷新去掉了写权限的页面
+        drop(mapping);
+        for segment in segments.iter().filter(|s| s.map_type == MapType::Alias) {
+            let alias_vpn = segment.page_range().start;
+            let frame = self
+                .allocated_pairs
+                .iter()
+                .find(|(vpn, _)| *vpn == alias_vpn)
+                .map(|(_, frame)| frame)
+                .ok_or("alias page is not backed by an allocated frame")?;
+            let original_vpn = self
+                .allocated_pairs
+                .iter()
+                .find(|(vpn, f)| Arc::ptr_eq(f, frame) && !self.is_alias(*vpn))
+                .map(|(vpn, _)| *vpn)
+                .ok_or("alias page has no original page")?;
+            dest.map_alias(original_vpn, alias_vpn, segment.flags)?;
+        }
+        Ok(())
+    }
+
+    /// 将 `src` 中 `src_range` 的页面以写时复制的方式共享到从 `dest_start` 开始的同样数量的页面
+    ///
+    /// 例如让多个进程共用只加载一次的共享库。两边共用同一个帧，并同时去掉页表项的写权限，
+    /// 之后任何一方写入时，与 [`MemorySet::clone_into`] 相同，由缺页异常为其复制出单独的帧。
+    /// `flags` 为新字段的权限，其中的写权限在第一次写入之前只记录在字段中。
+    ///
+    /// `src_range` 中的每个页面都必须已经分配了物理帧，且不能被别名共用，否则返回 `Err`，此时两边都不会被修改。
+    pub fn map_cow_from(
+        &mut self,
+        src: &mut MemorySet,
+        src_range: Range<VirtualPageNumber>,
+        dest_start: VirtualPageNumber,
+        flags: Flags,
+    ) -> MemoryResult<()> {
+        let dest_range = Range::from(dest_start..dest_start + src_range.len());
+        let flags = flags | self.mapping.default_flags_for(dest_range.into());
+        let segment = Segment {
+            map_type: MapType::Framed,
+            range: dest_range.into::<VirtualAddress>(),
+            flags,
+        };
+        if !segment.in_correct_half() {
+            return Err("segment is in the wrong half of address space");
+        }
+        if segment.maps_user_null_page() {
+            return Err("user segment cannot map the null page");
+        }
+        if self.overlap_with(dest_range) {
+            return Err("destination range overlaps an existing segment");
+        }
+        self.check_frame_limit(dest_range.len())?;
+        // 先找齐所有的帧，保证出错时不做任何修改
+        let aliased = src.aliased_frames();
+        let frames = src_range
+            .iter()
+            .map(|vpn| {
+                src.allocated_pairs
+                    .iter()
+                    .find(|(v, _)| *v == vpn)
+                    .map(|(_, frame)| frame.clone())
+                    .ok_or("page to share is not backed by an allocated frame")
+            })
+            .collect::<MemoryResult<Vec<Arc<FrameTracker>>>>()?;
+        if frames
+            .iter()
+            .any(|frame| aliased.contains(&frame.page_number()))
+        {
+            return Err("page to share is used by an alias");
+        }
+        let mut src_mapping = src.mapping.begin_batch();
+        for vpn in src_range.iter() {
+            let src_flags = src_mapping.entry_flags(vpn).ok_or("page is not mapped")?;
+            if src_flags.contains(Flags::WRITABLE) {
+                src_mapping.set_flags(vpn, src_flags - Flags::WRITABLE)?;
+            }
+        }
+        drop(src_mapping);
+        for (vpn, frame) in dest_range.iter().zip(frames) {
+            self.mapping.map_one(
+                vpn,
+                frame.page_number(),
+                (flags - Flags::WRITABLE) | Flags::VALID,
+            )?;
+            self.allocated_pairs.push((vpn, frame));
+        }
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// 页面是否属于某个 [`MapType::Alias`] 字段
+    fn is_alias(&self, vpn: VirtualPageNumber) -> bool {
+        self.segments
+            .iter()
+            .any(|s| s.map_type == MapType::Alias && s.page_range().contains(vpn))
+    }
+
+    /// 被 [`MapType::Alias`] 字段共用的所有帧
+    fn aliased_frames(&self) -> Vec<PhysicalPageNumber> {
+        self.allocated_pairs
+            .iter()
+            .filter(|(vpn, _)| self.is_alias(*vpn))
+            .map(|(_, frame)| frame.page_number())
+            .collect()
+    }
+
+    /// 将一个页面迁移到新的物理帧上，返回原来的帧
+    ///
+    /// 页面的内容会被复制到 `new_frame` 中，页表项随之指向新的帧，进程本身不会察觉。
+    /// 如果原来的帧还被共享，除非指定 `allow_shared`，否则返回 `Err`；
+    /// 即使允许，也只有当前的映射会改为使用新的帧。
+    pub fn migrate_page(
+        &mut self,
+        vpn: VirtualPageNumber,
+        mut new_frame: FrameTracker,
+        allow_shared: bool,
+    ) -> MemoryResult<Arc<FrameTracker>> {
+        let index = self
+            .allocated_pairs
+            .iter()
+            .position(|(v, _)| *v == vpn)
+            .ok_or("page to migrate is not backed by an allocated frame")?;
+        if !allow_shared && Arc::strong_count(&self.allocated_pairs[index].1) > 1 {
+            return Err("page to migrate is shared");
+        }
+        // 复制数据，然后修改页表项
+        self.allocated_pairs[index]
+            .1
+            .clone_contents_into(&mut new_frame);
+        self.mapping.remap_one(vpn, new_frame.page_number())?;
+        Ok(core::mem::replace(
+            &mut self.allocated_pairs[index].1,
+            Arc::new(new_frame),
+        ))
+    }
+
+    /// 让页面改为使用与其他页面共用的帧 `shared`，返回原来的帧，用于合并内容相同的页面
+    ///
+    /// 调用者需要事先确认两个帧的内容相同，这里不做比较。页表项去掉写权限，
+    /// 之后的写入会触发缺页异常，由写时复制重新得到单独的帧（见 [`MemorySet::handle_page_fault`]）。
+    /// 返回的帧如果没有其他引用，drop 时即被释放。
+    pub fn replace_frame(
+        &mut self,
+        vpn: VirtualPageNumber,
+        shared: Arc<FrameTracker>,
+    ) -> MemoryResult<Arc<FrameTracker>> {
+        let index = self
+            .allocated_pairs
+            .iter()
+            .position(|(v, _)| *v == vpn)
+            .ok_or("page to merge is not backed by an allocated frame")?;
+        if Arc::ptr_eq(&self.allocated_pairs[index].1, &shared) {
+            return Err("page already uses the shared frame");
+        }
+        let flags = self.mapping.entry_flags(vpn).ok_or("page is not mapped")?;
+        let mut mapping = self.mapping.begin_batch();
+        mapping.remap_one(vpn, shared.page_number())?;
+        mapping.set_flags(vpn, flags - Flags::WRITABLE)?;
+        Ok(core::mem::replace(
+            &mut self.allocated_pairs[index].1,
+            shared,
+        ))
+    }
+
+    /// 由分配的物理帧支持的虚拟页面数量
+    ///
+    /// 即常说的 RSS，按需映射的字段中尚未缺页的部分不计入，另见 [`MemorySet::virtual_size`]
+    pub fn resident_pages(&self) -> usize {
+        self.allocated_pairs.len()
+    }
+
+    /// 所有字段占用的虚拟地址空间大小（字节），按整页计算
+    ///
+    /// 即常说的 VSZ，包括按需映射、文件映射等尚未分配物理帧的部分，以及线性映射和 ROM。
+    /// 与 [`MemorySet::resident_pages`] 的差别就是预留但尚未使用的部分
+    pub fn virtual_size(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.page_range().len() * PAGE_SIZE)
+            .sum()
+    }
+
+    /// 实际占用的物理帧数量
+    ///
+    /// 别名或共享的帧会出现在多个虚拟页面中，这里只计算一次，
+    /// 因此和 [`MemorySet::resident_pages`] 不同，它反映的是真实的物理内存占用。
+    pub fn unique_frames(&self) -> usize {
+        let mut ppns: Vec<PhysicalPageNumber> = self
+            .allocated_pairs
+            .iter()
+            .map(|(_, frame)| frame.page_number())
+            .collect();
+        ppns.sort_unstable();
+        ppns.dedup();
+        ppns.len()
+    }
+
+    /// 按地址顺序打印所有字段，每个字段一行
+    ///
+    /// 每行格式见 [`Segment`] 的 `Display`
+    pub fn print_summary(&self) {
+        for segment in self.sorted_segments() {
+            println!("{}", segment);
+        }
+    }
+
+    /// 按 `/proc/<pid>/maps` 的格式输出所有字段，每个字段一行，按起始地址排序
+    ///
+    /// 每行依次为 `start-end perms offset dev inode path`，例如
+    /// `ffffffff80200000-ffffffff80209000 r-xs 00000000 00:00 0 [linear]`。
+    /// 权限的最后一位表示页面是否与其他映射共用：线性映射、ROM、别名和文件映射为 `s`，其余为 `p`。
+    /// 文件映射的 offset 为文件偏移，其余为 0；路径一栏为映射类型的名称。
+    /// 首尾相接且权限相同的线性映射字段合并为一行。
+    pub fn format_maps(&self) -> String {
+        write_maps(self.sorted_segments().into_iter())
+    }
+
+    /// 与 [`MemorySet::format_maps`] 相同，但只输出与 `range` 重叠的字段（见 [`MemorySet::segments_in_range`]）
+    pub fn format_maps_in(&self, range: Range<VirtualPageNumber>) -> String {
+        write_maps(self.segments_in_range(range))
+    }
+
+    /// 按起始地址的顺序迭代与 `range` 重叠的字段
+    ///
+    /// 区间都是左闭右开的，只在端点处相接的字段不算重叠。
+    /// 字段之间互不重叠，按起始地址排序后结束地址也是递增的，因此排序之后只需二分找到第一个结束于 `range` 之后的字段，
+    /// 再取到第一个开始于 `range` 之后的字段为止
+    pub fn segments_in_range(
+        &self,
+        range: Range<VirtualPageNumber>,
+    ) -> impl Iterator<Item = &Segment> {
+        let segments = self.sorted_segments();
+        // 结束不晚于 range.start 的字段都在前面
+        let first = match segments.binary_search_by(|segment| {
+            if segment.page_range().end <= range.start {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        }) {
+            Ok(index) | Err(index) => index,
+        };
+        segments
+            .into_iter()
+            .skip(first)
+            .take_while(move |segment| segment.page_range().start < range.end)
+    }
+
+    /// 按起始地址排序的所有字段
+    fn sorted_segments(&self) -> Vec<&Segment> {
+        let mut segments: Vec<&Segment> = self.segments.iter().collect();
+        segments.sort_unstable_by_key(|segment| segment.range.start);
+        segments
+    }
+
+    /// 检查一段地址是否完全位于允许 `access` 这种访问的用户字段中
+    ///
+    /// 用于检查系统调用中用户传入的缓冲区。按需映射的页面即使尚未分配也视为可以访问，
+    /// 内核访问时会触发缺页异常并分配。
+    pub fn check_range(&self, range: Range<VirtualAddress>, access: AccessType) -> bool {
+        range.to_page_range().iter().all(|vpn| {
+            self.segments.iter().any(|segment| {
+                segment.page_range().contains(vpn)
+                    && segment
+                        .flags_of(vpn)
+                        .contains(Flags::USER | access.required_flag())
+            })
+        })
+    }
+
+    /// 找出被意外映射到多个虚拟页面的物理页，以及被映射为普通页面的页表
+    ///
+    /// 线性映射的字段不参与检查，因为所有物理帧都会在内核的线性映射中出现一次；
+    /// [`MapType::Alias`] 是合法的共用方式，不计入映射的次数。
+    /// 返回每个有问题的物理页号及映射到它的所有虚拟页号，正常情况下应当为空。
+    /// 页表的帧被释放后仍被引用、又被重新分配时，就会在这里表现出来。
+    pub fn find_physical_conflicts(&self) -> Vec<(PhysicalPageNumber, Vec<VirtualPageNumber>)> {
+        // 每个物理页：映射到它的虚拟页号，以及其中不是别名的数量
+        let mut mapped: BTreeMap<PhysicalPageNumber, (Vec<VirtualPageNumber>, usize)> =
+            BTreeMap::new();
+        for segment in self.segments.iter() {
+            if segment.map_type == MapType::Linear {
+                continue;
+            }
+            for vpn in segment.page_range().iter() {
+                if let Some(pa) = self.mapping.translate(vpn.into()) {
+                    let (vpns, count) = mapped.entry(PhysicalPageNumber::floor(pa)).or_default();
+                    vpns.push(vpn);
+                    if segment.map_type != MapType::Alias {
+                        *count += 1;
+                    }
+                }
+            }
+        }
+        let page_tables: Vec<PhysicalPageNumber> = self.mapping.page_table_ppns().collect();
+        mapped
+            .into_iter()
+            .filter(|(ppn, (_vpns, count))| *count > 1 || page_tables.contains(ppn))
+            .map(|(ppn, (vpns, _count))| (ppn, vpns))
+            .collect()
+    }
+
     /// 检测一段内存区域和已有的是否存在重叠区域
     pub fn overlap_with(&self, range: Range<VirtualPageNumber>) -> bool {
         for seg in self.segments.iter() {
@@ -3,15 +3,27 @@
 //! 每个线程保存一个 [`Mapping`]，其中记录了所有的字段 [`Segment`]。
 //! 同时，也要追踪为页表或字段分配的所有物理页，目的是 drop 掉之后可以安全释放所有资源。
 
+mod address_space;
 #[allow(clippy::module_inception)]
 mod mapping;
 mod memory_set;
+mod oom;
 mod page_table;
 mod page_table_entry;
 mod segment;
+#[cfg(feature = "map_timing")]
+mod timing;
+mod tlb;
+mod walker;
 
-pub use mapping::Mapping;
+pub use address_space::{AddressSpace, HEAP_STACK_COLLISION};
+pub use mapping::{FlagsSnapshot, FlushBatch, Mapping, SatpMode};
 pub use memory_set::MemorySet;
+pub use oom::{OomAction, OomHandler, OOM_KILLED};
 pub use page_table::{PageTable, PageTableTracker};
-pub use page_table_entry::{Flags, PageTableEntry};
-pub use segment::{MapType, Segment};
+pub use page_table_entry::{AccessType, Flags, PageTableEntry};
+pub use segment::{write_maps, Backing, FileBacking, MapType, MappedFile, Segment};
+#[cfg(feature = "map_timing")]
+pub use timing::{timing_report, FunctionTiming, MapTimings};
+pub use tlb::TlbShootdown;
+pub use walker::{PageTableWalker, WalkStep};
@@ -0,0 +1,30 @@
+//! 分配物理帧失败时的处理方式 [`OomHandler`]
+
+/// 处理方式返回 [`OomAction::Kill`] 时，分配失败返回的错误
+///
+/// 调用者可以与它比较，终止当前的进程而不是只让这一次系统调用失败
+pub const OOM_KILLED: &str = "out of memory, the process should be killed";
+
+/// 分配失败之后应当如何继续
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OomAction {
+    /// 处理方式已经释放了一些帧（例如换出页面），再试一次
+    Retry,
+    /// 将分配器的错误原样返回给调用者
+    Fail,
+    /// 返回 [`OOM_KILLED`]，由调用者终止进程
+    Kill,
+}
+
+/// 映射分配物理帧失败时调用的处理方式，通过 [`Mapping::set_oom_handler`] 设置
+///
+/// 换出页面或者选择终止某个进程的策略可以在这里接入，而不必修改每一处分配。
+/// 未设置时等同于总是返回 [`OomAction::Fail`]。
+///
+/// [`Mapping::set_oom_handler`]: crate::memory::mapping::Mapping::set_oom_handler
+pub trait OomHandler: Send + Sync {
+    /// 还需要 `needed` 个帧时分配失败，决定之后怎么做
+    ///
+    /// 只有确实释放了帧时才应返回 [`OomAction::Retry`]，否则分配会一直重试下去
+    fn on_oom(&self, needed: usize) -> OomAction;
+}
@@ -28,6 +28,10 @@ impl PageTable {
     pub fn zero_init(&mut self) {
         self.entries = [Default::default(); PAGE_SIZE / 8];
     }
+    /// 是否所有页表项都为空
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(PageTableEntry::is_empty)
+    }
 }
 
 /// 类似于 [`FrameTracker`]，用于记录某一个内存中页表
@@ -36,18 +40,37 @@ impl PageTable {
 /// 而 `PageTableTracker` 会保存在某个线程的元数据中（也就是在操作系统的堆上），指向其真正的页表。
 ///
 /// 当 `PageTableTracker` 被 drop 时，会自动 drop `FrameTracker`，进而释放帧。
-pub struct PageTableTracker(pub FrameTracker);
+pub struct PageTableTracker {
+    /// 页表所在的帧
+    frame: FrameTracker,
+    /// 页表位于第几级，根页表为 0
+    level: usize,
+}
 
 impl PageTableTracker {
-    /// 将一个分配的帧清零，形成空的页表
-    pub fn new(frame: FrameTracker) -> Self {
-        let mut page_table = Self(frame);
-        page_table.zero_init();
+    /// 将一个已经清零的帧包装为空的页表
+    ///
+    /// 页表中所有未写入的页表项都会被视为空项，因此传入的帧必须已经清零，
+    /// 即应当由 [`FRAME_ALLOCATOR`] 的 `alloc_zeroed()` 分配。回收的帧中可能残留旧的数据，
+    /// 如果不清零，这些数据会被当作有效的映射。
+    ///
+    /// [`FRAME_ALLOCATOR`]: static@crate::memory::FRAME_ALLOCATOR
+    ///
+    /// 在 debug 模式下会检查所有页表项均为空，以便在帧被回收再分配时立即发现残留数据。
+    ///
+    /// `level` 为页表位于第几级，根页表为 0
+    pub fn new(frame: FrameTracker, level: usize) -> Self {
+        let page_table = Self { frame, level };
+        debug_assert!(page_table.is_empty(), "page table frame is not zeroed");
         page_table
     }
     /// 获取物理页号
     pub fn page_number(&self) -> PhysicalPageNumber {
-        self.0.page_number()
+        self.frame.page_number()
+    }
+    /// 页表位于第几级，根页表为 0
+    pub fn level(&self) -> usize {
+        self.level
     }
 }
 
@@ -57,18 +80,19 @@ impl PageTableTracker {
 impl core::ops::Deref for PageTableTracker {
     type Target = PageTable;
     fn deref(&self) -> &Self::Target {
-        self.0.address().deref_kernel()
+        self.frame.address().deref_kernel()
     }
 }
 
 impl core::ops::DerefMut for PageTableTracker {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.0.address().deref_kernel()
+        self.frame.address().deref_kernel()
     }
 }
 
 // 因为 PageTableEntry 和具体的 PageTable 之间没有生命周期关联，所以返回 'static 引用方便写代码
 impl PageTableEntry {
+    /// 通过线性映射访问页表项指向的下一级页表
     pub fn get_next_table(&self) -> &'static mut PageTable {
         self.address().deref_kernel()
     }
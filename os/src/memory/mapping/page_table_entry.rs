@@ -10,13 +10,13 @@
 //! RISC-V 64 现有两种地址长度：39 位和 48 位，其中 Sv39 的虚拟地址就包括三级页表和页内偏移。
 //! `3 * 9 + 12 = 39`
 //!
-//! 我们使用 Sv39，Sv48 同理，只是它具有四级页表。
+//! 我们默认使用 Sv39，也支持具有四级页表的 Sv48，两者的页表项结构相同。
 
 use crate::memory::address::*;
 use bit_field::BitField;
 use bitflags::*;
 
-/// Sv39 结构的页表项
+/// Sv39 / Sv48 结构的页表项
 #[derive(Copy, Clone, Default)]
 pub struct PageTableEntry(usize);
 
@@ -92,6 +92,44 @@ bitflags! {
     }
 }
 
+/// 常用的权限组合，都已经包含 [`Flags::VALID`]
+///
+/// 映射时漏掉 `VALID` 会使页面看似映射成功，访问时却触发缺页异常。单独的标志位仍然可以使用
+impl Flags {
+    /// 有效、只读
+    pub const R: Flags = Flags::from_bits_truncate(Flags::VALID.bits() | Flags::READABLE.bits());
+    /// 有效、可读写
+    pub const RW: Flags = Flags::from_bits_truncate(Flags::R.bits() | Flags::WRITABLE.bits());
+    /// 有效、可读可执行
+    pub const RX: Flags = Flags::from_bits_truncate(Flags::R.bits() | Flags::EXECUTABLE.bits());
+    /// 有效、可读写可执行
+    pub const RWX: Flags = Flags::from_bits_truncate(Flags::RW.bits() | Flags::EXECUTABLE.bits());
+    /// 有效、用户可读写
+    pub const URW: Flags = Flags::from_bits_truncate(Flags::RW.bits() | Flags::USER.bits());
+}
+
+/// 访存的类型，用于区分缺页异常的原因
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessType {
+    /// 读取数据
+    Load,
+    /// 写入数据
+    Store,
+    /// 取指令
+    Fetch,
+}
+
+impl AccessType {
+    /// 进行这种访问需要页面具有的标志位
+    pub fn required_flag(self) -> Flags {
+        match self {
+            AccessType::Load => Flags::READABLE,
+            AccessType::Store => Flags::WRITABLE,
+            AccessType::Fetch => Flags::EXECUTABLE,
+        }
+    }
+}
+
 macro_rules! implement_flags {
     ($field: ident, $name: ident, $quote: literal) => {
         impl Flags {
@@ -1,18 +1,157 @@
 //! 映射类型 [`MapType`] 和映射片段 [`Segment`]
 
-use crate::memory::{address::*, mapping::Flags, range::Range};
+use crate::memory::{
+    address::*,
+    config::{
+        memory_end, KERNEL_ADDRESS_MIN, KERNEL_MAP_OFFSET, MEMORY_START_ADDRESS, PAGE_SIZE,
+        USER_ADDRESS_MAX,
+    },
+    mapping::Flags,
+    range::Range,
+    MemoryResult,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
+
+/// 可以被映射到内存中的文件
+///
+/// `offset` 为文件中的字节偏移，总是页对齐的；`buf` 为一整个页面。
+/// 读取超出文件末尾的部分时，应当保持 `buf` 中对应的内容不变（即为 0）。
+pub trait MappedFile: Send + Sync {
+    /// 从文件 `offset` 处读取一个页面
+    fn read_page(&self, offset: usize, buf: &mut [u8]) -> MemoryResult<()>;
+    /// 将一个页面写回文件 `offset` 处
+    fn write_page(&self, offset: usize, buf: &[u8]) -> MemoryResult<()>;
+}
+
+/// 文件映射的来源：文件，以及字段起始处对应的文件偏移
+#[derive(Clone)]
+pub struct FileBacking {
+    /// 被映射的文件
+    pub file: Arc<dyn MappedFile>,
+    /// 字段起始地址对应的文件偏移，必须页对齐
+    pub offset: usize,
+}
+
+impl FileBacking {
+    /// 给定页面对应的文件偏移
+    pub fn offset_of(&self, segment: &Segment, vpn: VirtualPageNumber) -> usize {
+        self.offset + (vpn - segment.page_range().start) * PAGE_SIZE
+    }
+}
+
+impl PartialEq for FileBacking {
+    /// 同一个文件的同一个偏移
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.file) as *const u8 == Arc::as_ptr(&other.file) as *const u8
+            && self.offset == other.offset
+    }
+}
+
+impl Eq for FileBacking {}
+
+impl fmt::Debug for FileBacking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileBacking")
+            .field("file", &Arc::as_ptr(&self.file))
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+/// 按帧映射的字段中，尚未映射的页面从哪里得到内容，由 [`Segment::backing`] 给出
+///
+/// 缺页时 [`MemorySet::handle_page_fault`] 据此决定如何填充页面，
+/// 按需映射、文件映射和换出的页面因此可以用同一种方式描述。
+///
+/// [`MemorySet::handle_page_fault`]: crate::memory::MemorySet::handle_page_fault
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Backing {
+    /// 匿名内存，页面在映射字段时就已分配，缺页说明访问不被允许
+    Anonymous,
+    /// 第一次访问时分配清零的页面
+    Zero,
+    /// 第一次访问时分配页面并从文件读入
+    File(FileBacking),
+    /// 页面被换出到交换区，目前还没有映射类型会产生它
+    Swap,
+}
 
 /// 映射的类型
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum MapType {
     /// 线性映射，操作系统使用
     Linear,
     /// 按帧分配映射
     Framed,
+    /// 按帧分配映射，但每个页面有各自的权限标志
+    ///
+    /// 按页面在字段中的顺序记录标志，此时 [`Segment::flags`] 为所有页面标志的并集
+    FramedWithFlags(Vec<Flags>),
+    /// 与同一映射中另一个页面共用物理帧，只能通过 [`MemorySet::map_alias`] 建立
+    ///
+    /// [`MemorySet::map_alias`]: crate::memory::MemorySet::map_alias
+    Alias,
+    /// 按需分配映射，页面在第一次访问时才分配，内容为 0
+    ///
+    /// 由 [`MemorySet::handle_page_fault`] 建立映射，在此之前不占用物理帧，也不创建页表。
+    ///
+    /// [`MemorySet::handle_page_fault`]: crate::memory::MemorySet::handle_page_fault
+    Lazy,
+    /// 映射文件，页面在第一次访问时才分配并从文件读入
+    ///
+    /// 由 [`MemorySet::handle_page_fault`] 建立映射。字段的起始地址必须页对齐。
+    ///
+    /// [`MemorySet::handle_page_fault`]: crate::memory::MemorySet::handle_page_fault
+    FileBacked(FileBacking),
+    /// 只读的设备 ROM，从给定的物理页号开始按顺序映射，只能通过 [`MemorySet::map_rom`] 建立
+    ///
+    /// 与 [`MapType::Linear`] 相同，映射时不分配物理帧，复制地址空间时直接共用；
+    /// 因为没有写权限，也不会参与写时复制。
+    ///
+    /// [`MemorySet::map_rom`]: crate::memory::MemorySet::map_rom
+    Rom(PhysicalPageNumber),
+    /// 影子栈，按帧分配映射，只能通过 [`MemorySet::map_shadow_stack`] 建立
+    ///
+    /// 页面对用户只读，普通的写入会触发缺页异常并被拒绝；
+    /// 只有内核通过 [`MemorySet::shadow_stack_write`] 才能写入。
+    ///
+    /// [`MemorySet::map_shadow_stack`]: crate::memory::MemorySet::map_shadow_stack
+    /// [`MemorySet::shadow_stack_write`]: crate::memory::MemorySet::shadow_stack_write
+    ShadowStack,
+    /// 所有地址空间共用的一页内核数据，映射到给定的物理页号，只能通过 [`MemorySet::map_trap_scratch`] 建立
+    ///
+    /// 帧由全局的表持有，从不释放，字段本身不拥有它。与 [`MapType::Rom`] 相同，
+    /// 映射时不分配物理帧，复制地址空间时直接共用，但页面可写，也不会参与写时复制。
+    ///
+    /// [`MemorySet::map_trap_scratch`]: crate::memory::MemorySet::map_trap_scratch
+    Shared(PhysicalPageNumber),
+}
+
+impl MapType {
+    /// 是否在发生缺页异常时才建立映射
+    pub fn is_lazy(&self) -> bool {
+        matches!(self, MapType::Lazy | MapType::FileBacked(_))
+    }
+
+    /// 映射类型的名称，不包含附带的数据
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapType::Linear => "Linear",
+            MapType::Framed => "Framed",
+            MapType::FramedWithFlags(_) => "FramedWithFlags",
+            MapType::Alias => "Alias",
+            MapType::Lazy => "Lazy",
+            MapType::FileBacked(_) => "FileBacked",
+            MapType::Rom(_) => "Rom",
+            MapType::ShadowStack => "ShadowStack",
+            MapType::Shared(_) => "Shared",
+        }
+    }
 }
 
 /// 一个映射片段（对应旧 tutorial 的 `MemoryArea`）
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Segment {
     /// 映射类型
     pub map_type: MapType,
@@ -22,21 +161,286 @@ pub struct Segment {
     pub flags: Flags,
 }
 
+/// 单行的概要，依次为映射类型、虚拟地址区间、页面数和 `rwxug` 权限，例如
+/// `Linear          0xffffffff80200000-0xffffffff80209000      9 r-x--`
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag: Flags, c: char| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{:<15} {:#x}-{:#x} {:>6} {}{}{}{}{}",
+            self.map_type.name(),
+            self.range.start.0,
+            self.range.end.0,
+            self.page_range().len(),
+            flag(Flags::READABLE, 'r'),
+            flag(Flags::WRITABLE, 'w'),
+            flag(Flags::EXECUTABLE, 'x'),
+            flag(Flags::USER, 'u'),
+            flag(Flags::GLOBAL, 'g'),
+        )
+    }
+}
+
 impl Segment {
     /// 遍历对应的物理地址（如果可能）
     pub fn iter_mapped(&self) -> Option<impl Iterator<Item = PhysicalPageNumber>> {
         match self.map_type {
             // 线性映射可以直接将虚拟地址转换
             MapType::Linear => Some(self.page_range().into().iter()),
+            // ROM 和共用的页面从给定的物理页号开始连续映射
+            MapType::Rom(start) | MapType::Shared(start) => Some(
+                Range::<PhysicalPageNumber>::from(start..start + self.page_range().len()).iter(),
+            ),
             // 按帧映射无法直接获得物理地址，需要分配
-            MapType::Framed => None,
+            MapType::Framed
+            | MapType::FramedWithFlags(_)
+            | MapType::Alias
+            | MapType::Lazy
+            | MapType::FileBacked(_)
+            | MapType::ShadowStack => None,
+        }
+    }
+
+    /// 尚未映射的页面从哪里得到内容，由映射类型决定
+    ///
+    /// 线性映射和 ROM 不分配物理帧，也就没有后备的存储，返回 `None`
+    pub fn backing(&self) -> Option<Backing> {
+        match &self.map_type {
+            MapType::Linear | MapType::Rom(_) | MapType::Shared(_) => None,
+            MapType::Framed
+            | MapType::FramedWithFlags(_)
+            | MapType::Alias
+            | MapType::ShadowStack => Some(Backing::Anonymous),
+            MapType::Lazy => Some(Backing::Zero),
+            MapType::FileBacked(backing) => Some(Backing::File(backing.clone())),
         }
     }
 
+    /// 给定页面的权限标志
+    pub fn flags_of(&self, vpn: VirtualPageNumber) -> Flags {
+        match &self.map_type {
+            MapType::FramedWithFlags(flags) => flags[vpn - self.page_range().start],
+            _ => self.flags,
+        }
+    }
+
+    /// 字段是否位于与其权限相符的那一半地址空间中
+    ///
+    /// 带有 USER 位的字段必须位于 [`USER_ADDRESS_MAX`] 以下，其余字段必须位于 [`KERNEL_ADDRESS_MIN`] 以上
+    pub fn in_correct_half(&self) -> bool {
+        if self.flags.contains(Flags::USER) {
+            self.range.end <= USER_ADDRESS_MAX
+        } else {
+            self.range.start >= KERNEL_ADDRESS_MIN
+        }
+    }
+
+    /// 是否为包含 0 号页面的用户字段
+    ///
+    /// 用户程序解引用空指针时应当触发缺页异常而被终止，因此用户字段不能映射 0 号页面。
+    /// 内核字段不受限制，不过它们本来就位于高地址。
+    pub fn maps_user_null_page(&self) -> bool {
+        self.flags.contains(Flags::USER) && self.page_range().contains(VirtualPageNumber(0))
+    }
+
+    /// 是否为设备的 MMIO 字段，即线性映射到可用内存区域以下的物理地址（见 [`MemorySet::map_mmio`]）
+    ///
+    /// [`MemorySet::map_mmio`]: crate::memory::MemorySet::map_mmio
+    pub fn is_mmio(&self) -> bool {
+        self.map_type == MapType::Linear
+            && self.range.start.0 >= KERNEL_MAP_OFFSET
+            && self.range.end.0 - KERNEL_MAP_OFFSET <= MEMORY_START_ADDRESS.0
+    }
+
+    /// 线性映射的字段是否指向存在的物理地址
+    ///
+    /// 线性映射要么完全位于可用内存区域中，要么是完全位于其下的设备区域（见 [`Segment::is_mmio`]）。
+    /// 链接脚本或配置出错时，字段可能指向并不存在的内存，这里在映射时就发现，而不是等到访问时才出现总线错误。
+    /// 其他映射类型总是返回 `true`
+    pub fn linear_in_bounds(&self) -> bool {
+        if self.map_type != MapType::Linear {
+            return true;
+        }
+        if self.range.start.0 < KERNEL_MAP_OFFSET {
+            return false;
+        }
+        let start = self.range.start.0 - KERNEL_MAP_OFFSET;
+        let end = self.range.end.0 - KERNEL_MAP_OFFSET;
+        end <= memory_end().0 && (start >= MEMORY_START_ADDRESS.0 || self.is_mmio())
+    }
+
     /// 将地址相应地上下取整，获得虚拟页号区间
     pub fn page_range(&self) -> Range<VirtualPageNumber> {
-        Range::from(
-            VirtualPageNumber::floor(self.range.start)..VirtualPageNumber::ceil(self.range.end),
-        )
+        self.range.to_page_range()
+    }
+
+    /// 如果 `next` 可以接在这个字段之后合并为一个字段，返回合并后的映射类型，见 [`MemorySet::coalesce`]
+    ///
+    /// [`MemorySet::coalesce`]: crate::memory::MemorySet::coalesce
+    pub fn merged_type(&self, next: &Segment) -> Option<MapType> {
+        if self.flags != next.flags
+            || self.range.end != next.range.start
+            || self.range.end.page_offset() != 0
+        {
+            return None;
+        }
+        match (&self.map_type, &next.map_type) {
+            (MapType::Linear, MapType::Linear)
+            | (MapType::Framed, MapType::Framed)
+            | (MapType::Alias, MapType::Alias)
+            | (MapType::Lazy, MapType::Lazy)
+            | (MapType::ShadowStack, MapType::ShadowStack) => Some(self.map_type.clone()),
+            (MapType::FramedWithFlags(flags), MapType::FramedWithFlags(next_flags)) => {
+                let mut flags = flags.clone();
+                flags.extend_from_slice(next_flags);
+                Some(MapType::FramedWithFlags(flags))
+            }
+            (MapType::Rom(start), MapType::Rom(next_start))
+                if *next_start == *start + self.page_range().len() =>
+            {
+                Some(self.map_type.clone())
+            }
+            (MapType::FileBacked(backing), MapType::FileBacked(next_backing))
+                if *next_backing
+                    == (FileBacking {
+                        file: backing.file.clone(),
+                        offset: backing.offset + (self.range.end - self.range.start),
+                    }) =>
+            {
+                Some(self.map_type.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// 将按起始地址排序的字段写成 `/proc/<pid>/maps` 的格式，见 [`MemorySet::format_maps`]
+///
+/// [`MemorySet::format_maps`]: crate::memory::MemorySet::format_maps
+pub fn write_maps<'a>(segments: impl Iterator<Item = &'a Segment>) -> String {
+    let mut output = String::new();
+    let mut segments = segments.peekable();
+    while let Some(segment) = segments.next() {
+        let mut end = segment.range.end;
+        if segment.map_type == MapType::Linear {
+            while let Some(next) = segments.peek() {
+                if next.map_type != MapType::Linear
+                    || next.flags != segment.flags
+                    || next.range.start != end
+                {
+                    break;
+                }
+                end = next.range.end;
+                segments.next();
+            }
+        }
+        let flag = |flag: Flags, c: char| if segment.flags.contains(flag) { c } else { '-' };
+        let (shared, offset) = match &segment.map_type {
+            MapType::Linear | MapType::Rom(_) | MapType::Shared(_) | MapType::Alias => ('s', 0),
+            MapType::FileBacked(backing) => ('s', backing.offset),
+            MapType::Framed
+            | MapType::FramedWithFlags(_)
+            | MapType::Lazy
+            | MapType::ShadowStack => ('p', 0),
+        };
+        // 写入 String 不会失败
+        let _ = writeln!(
+            output,
+            "{:08x}-{:08x} {}{}{}{} {:08x} 00:00 0 [{}]",
+            segment.range.start.0,
+            end.0,
+            flag(Flags::READABLE, 'r'),
+            flag(Flags::WRITABLE, 'w'),
+            flag(Flags::EXECUTABLE, 'x'),
+            shared,
+            offset,
+            segment.map_type.name().to_lowercase(),
+        );
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// 覆盖 `pages` 中页面的字段
+    fn segment(map_type: MapType, pages: core::ops::Range<usize>, flags: Flags) -> Segment {
+        Segment {
+            map_type,
+            range: Range::<VirtualPageNumber>::from(pages).into(),
+            flags,
+        }
+    }
+
+    #[test]
+    fn merge_adjacent_segments_of_same_kind() {
+        let first = segment(MapType::Framed, 0x10..0x12, Flags::RW);
+        let next = segment(MapType::Framed, 0x12..0x14, Flags::RW);
+        assert_eq!(first.merged_type(&next), Some(MapType::Framed));
+        // 不相邻、权限不同或类型不同的字段都不合并
+        let apart = segment(MapType::Framed, 0x13..0x14, Flags::RW);
+        assert_eq!(first.merged_type(&apart), None);
+        let read_only = segment(MapType::Framed, 0x12..0x14, Flags::R);
+        assert_eq!(first.merged_type(&read_only), None);
+        let lazy = segment(MapType::Lazy, 0x12..0x14, Flags::RW);
+        assert_eq!(first.merged_type(&lazy), None);
+        // 结束地址没有页对齐时，下一个字段与它共用最后一页
+        let mut unaligned = first;
+        unaligned.range.end = VirtualAddress(0x11800);
+        let next = Segment {
+            range: Range::from(VirtualAddress(0x11800)..VirtualAddress(0x14000)),
+            ..next
+        };
+        assert_eq!(unaligned.merged_type(&next), None);
+    }
+
+    #[test]
+    fn merge_concatenates_page_flags() {
+        let first = segment(
+            MapType::FramedWithFlags(vec![Flags::R, Flags::RX]),
+            0..2,
+            Flags::RX,
+        );
+        let next = segment(MapType::FramedWithFlags(vec![Flags::RX]), 2..3, Flags::RX);
+        assert_eq!(
+            first.merged_type(&next),
+            Some(MapType::FramedWithFlags(vec![
+                Flags::R,
+                Flags::RX,
+                Flags::RX
+            ]))
+        );
+    }
+
+    #[test]
+    fn merge_rom_only_when_frames_continue() {
+        let first = segment(MapType::Rom(PhysicalPageNumber(0x100)), 0..4, Flags::R);
+        let next = segment(MapType::Rom(PhysicalPageNumber(0x104)), 4..6, Flags::R);
+        assert_eq!(first.merged_type(&next), Some(first.map_type.clone()));
+        let elsewhere = segment(MapType::Rom(PhysicalPageNumber(0x200)), 4..6, Flags::R);
+        assert_eq!(first.merged_type(&elsewhere), None);
+    }
+
+    #[test]
+    fn maps_format() {
+        let kernel = VirtualPageNumber::floor(VirtualAddress(0xffff_ffff_8020_0000)).0;
+        let segments = [
+            segment(MapType::Framed, 0x1000..0x1002, Flags::URW),
+            segment(MapType::Lazy, 0x2000..0x2004, Flags::RX | Flags::USER),
+            // 相邻的、权限相同的线性映射合并为一行，权限不同的则不合并
+            segment(MapType::Linear, kernel..kernel + 2, Flags::RX),
+            segment(MapType::Linear, kernel + 2..kernel + 3, Flags::RW),
+            segment(MapType::Linear, kernel + 3..kernel + 8, Flags::RW),
+        ];
+        assert_eq!(
+            write_maps(segments.iter()),
+            "01000000-01002000 rw-p 00000000 00:00 0 [framed]\n\
+             02000000-02004000 r-xp 00000000 00:00 0 [lazy]\n\
+             ffffffff80200000-ffffffff80202000 r-xs 00000000 00:00 0 [linear]\n\
+             ffffffff80202000-ffffffff80208000 rw-s 00000000 00:00 0 [linear]\n"
+        );
     }
 }
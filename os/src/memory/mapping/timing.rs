@@ -0,0 +1,89 @@
+//! 映射相关函数的耗时统计 [`timing_report`]，只在开启 `map_timing` feature 时存在
+//!
+//! 通过 `cycle` 寄存器计时，用于比较大页、批量刷新等优化前后的差别。
+//! 统计是包含式的：例如 [`MemorySet::new_kernel`] 的耗时包括了其中每次 `map_one` 的耗时。
+//!
+//! [`MemorySet::new_kernel`]: crate::memory::MemorySet::new_kernel
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::cycle;
+
+/// 一个函数的统计
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FunctionTiming {
+    /// 调用次数
+    pub calls: usize,
+    /// 所有调用的总周期数
+    pub cycles: usize,
+}
+
+/// 各个函数的统计，由 [`timing_report`] 得到
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MapTimings {
+    /// [`MemorySet::new_kernel`](crate::memory::MemorySet::new_kernel)
+    pub new_kernel: FunctionTiming,
+    /// [`MemorySet::add_segment`](crate::memory::MemorySet::add_segment)，所有分配并映射字段的函数都经过它
+    pub add_segment: FunctionTiming,
+    /// `Mapping::map_one`，即写入一个叶子页表项
+    pub map_one: FunctionTiming,
+}
+
+/// 被统计的函数
+#[derive(Copy, Clone)]
+pub(super) enum Timed {
+    NewKernel,
+    AddSegment,
+    MapOne,
+}
+
+/// 每个函数的调用次数和总周期数，下标为 [`Timed`]
+static CALLS: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static CYCLES: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// 计时一次调用，drop 时记录
+///
+/// 函数可能从多个位置返回（包括通过 `?` 返回错误），用 drop 记录可以覆盖所有情况
+pub(super) struct Timer {
+    function: Timed,
+    start: usize,
+}
+
+impl Timer {
+    /// 开始计时
+    pub(super) fn start(function: Timed) -> Self {
+        Self {
+            function,
+            start: cycle::read(),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let elapsed = cycle::read().wrapping_sub(self.start);
+        let index = self.function as usize;
+        CALLS[index].fetch_add(1, Ordering::Relaxed);
+        CYCLES[index].fetch_add(elapsed, Ordering::Relaxed);
+    }
+}
+
+/// 到目前为止各个函数的调用次数和总周期数
+pub fn timing_report() -> MapTimings {
+    let read = |function: Timed| FunctionTiming {
+        calls: CALLS[function as usize].load(Ordering::Relaxed),
+        cycles: CYCLES[function as usize].load(Ordering::Relaxed),
+    };
+    MapTimings {
+        new_kernel: read(Timed::NewKernel),
+        add_segment: read(Timed::AddSegment),
+        map_one: read(Timed::MapOne),
+    }
+}
@@ -0,0 +1,14 @@
+//! 多核下同步其他核的 TLB [`TlbShootdown`]
+
+use crate::memory::address::VirtualPageNumber;
+
+/// 通知其他核刷新 TLB 的方式
+///
+/// 修改一个同时在其他核上生效的映射后，仅刷新本核的 TLB 是不够的，
+/// 其他核可能仍然缓存着旧的页表项。通常需要通过核间中断（IPI）让它们各自执行 `sfence.vma`。
+pub trait TlbShootdown: Send + Sync {
+    /// 让 `harts` 中的每个核刷新 TLB
+    ///
+    /// `vpn` 为 `None` 时刷新所有页面，`asid` 为 `None` 时刷新所有地址空间
+    fn remote_flush(&self, harts: &[usize], vpn: Option<VirtualPageNumber>, asid: Option<u16>);
+}
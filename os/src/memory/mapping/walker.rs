@@ -0,0 +1,117 @@
+//! 遍历整个页表树的迭代器 [`PageTableWalker`]
+
+use crate::memory::{
+    address::*,
+    config::PAGE_SIZE,
+    mapping::{Mapping, PageTable, PageTableEntry},
+};
+
+/// 每个页表中的页表项数量
+const ENTRIES: usize = PAGE_SIZE / 8;
+
+/// 遍历中的一步，对应一个非空的页表项
+#[derive(Copy, Clone, Debug)]
+pub struct WalkStep<'a> {
+    /// 页表项所在页表的级数，根页表为 0
+    pub level: usize,
+    /// 页表项在页表中的下标
+    pub index: usize,
+    /// 页表项本身
+    pub entry: &'a PageTableEntry,
+    /// 从根页表到这个页表项的各级下标依次拼成的数
+    pub vpn_prefix: usize,
+    /// 页表项覆盖的区间中的第一个虚拟页号
+    ///
+    /// 由 `vpn_prefix` 补齐低几级的下标，并在高地址上按最高位扩展，
+    /// 与 [`VirtualPageNumber::floor`] 得到的页号一致
+    pub vpn: VirtualPageNumber,
+}
+
+impl WalkStep<'_> {
+    /// 是否为叶子页表项（最后一级的页表项或大页）
+    pub fn is_leaf(&self) -> bool {
+        !self.entry.has_next_level()
+    }
+}
+
+/// 按深度优先的顺序遍历映射中所有非空的页表项，由 [`Mapping::walker`] 创建
+///
+/// 先给出指向下一级页表的页表项，再依次给出下一级页表中的页表项。
+/// 不会分配内存，遍历的状态保存在固定大小的数组中。
+pub struct PageTableWalker<'a> {
+    /// 被遍历的映射
+    mapping: &'a Mapping,
+    /// 每一级正在遍历的页表、下一个要检查的下标，以及这个页表对应的各级下标
+    stack: [(PhysicalPageNumber, usize, usize); 4],
+    /// `stack` 中有效的层数，为 0 时遍历结束
+    depth: usize,
+}
+
+impl<'a> PageTableWalker<'a> {
+    /// 从映射的根页表开始遍历
+    pub(super) fn new(mapping: &'a Mapping, root_ppn: Option<PhysicalPageNumber>) -> Self {
+        let mut stack = [(PhysicalPageNumber(0), 0, 0); 4];
+        let depth = match root_ppn {
+            Some(ppn) => {
+                stack[0] = (ppn, 0, 0);
+                1
+            }
+            None => 0,
+        };
+        Self {
+            mapping,
+            stack,
+            depth,
+        }
+    }
+
+    /// 由各级下标得到页表项覆盖的第一个虚拟页号
+    fn first_vpn(&self, vpn_prefix: usize, level: usize) -> VirtualPageNumber {
+        let levels = self.mapping.mode().levels();
+        let vpn = vpn_prefix << (9 * (levels - 1 - level));
+        // 拼成的页号只有低 9 * 级数 位，将最高位扩展到虚拟页号的所有 52 位
+        let bits = 9 * levels;
+        if (vpn >> (bits - 1)) & 1 == 1 {
+            VirtualPageNumber(vpn | (((1 << 52) - 1) & !((1 << bits) - 1)))
+        } else {
+            VirtualPageNumber(vpn)
+        }
+    }
+}
+
+impl<'a> Iterator for PageTableWalker<'a> {
+    type Item = WalkStep<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let leaf_level = self.mapping.mode().levels() - 1;
+        while self.depth > 0 {
+            let level = self.depth - 1;
+            let (ppn, index, prefix) = self.stack[level];
+            if index == ENTRIES {
+                // 这一级的页表已经遍历完，回到上一级
+                self.depth -= 1;
+                continue;
+            }
+            self.stack[level].1 += 1;
+            let table: &'a PageTable = PhysicalAddress::from(ppn).deref_kernel();
+            let entry = &table.entries[index];
+            if entry.is_empty() {
+                continue;
+            }
+            let vpn_prefix = (prefix << 9) | index;
+            if level < leaf_level && entry.has_next_level() {
+                // 下一次从下一级页表的第一项继续
+                self.stack[level + 1] = (entry.page_number(), 0, vpn_prefix);
+                self.depth += 1;
+            }
+            return Some(WalkStep {
+                level,
+                index,
+                entry,
+                vpn_prefix,
+                vpn: self.first_vpn(vpn_prefix, level),
+            });
+        }
+        None
+    }
+}
@@ -12,6 +12,8 @@ pub mod frame;
 pub mod heap;
 pub mod mapping;
 pub mod range;
+#[cfg(feature = "self_test")]
+pub mod self_test;
 
 /// 一个缩写，模块中一些函数会使用
 pub type MemoryResult<T> = Result<T, &'static str>;
@@ -20,7 +22,10 @@ pub use {
     address::*,
     config::*,
     frame::FRAME_ALLOCATOR,
-    mapping::{Flags, MapType, MemorySet, Segment},
+    mapping::{
+        AccessType, AddressSpace, Backing, FileBacking, Flags, MapType, MappedFile, MemorySet,
+        Segment, TlbShootdown,
+    },
     range::Range,
 };
 
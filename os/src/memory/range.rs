@@ -1,5 +1,7 @@
 //! 表示一个页面区间 [`Range`]，提供迭代器功能
 
+use super::address::*;
+
 /// 表示一段连续的页面
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Range<T: From<usize> + Into<usize> + Copy> {
@@ -28,6 +30,11 @@ impl<T: From<usize> + Into<usize> + Copy> Range<T> {
         (self.start.into()..self.end.into()).map(T::from)
     }
 
+    /// 从 `end - 1` 到 `start` 倒序迭代区间中的所有页，用于从高地址向低地址处理栈等向下增长的区域
+    pub fn iter_rev(&self) -> impl Iterator<Item = T> {
+        (self.start.into()..self.end.into()).rev().map(T::from)
+    }
+
     /// 区间大小
     pub fn len(&self) -> usize {
         self.end.into() - self.start.into()
@@ -51,4 +58,137 @@ impl<T: From<usize> + Into<usize> + Copy> Range<T> {
     pub fn contains(&self, value: T) -> bool {
         self.start.into() <= value.into() && value.into() < self.end.into()
     }
+
+    /// 在 `at` 处将区间切开，得到 `[start, at)` 和 `[at, end)`
+    ///
+    /// `at` 必须位于 `[start, end]` 之中，等于端点时其中一半为空
+    pub fn split_at(&self, at: T) -> (Range<T>, Range<T>) {
+        assert!(self.start.into() <= at.into() && at.into() <= self.end.into());
+        (
+            Range {
+                start: self.start,
+                end: at,
+            },
+            Range {
+                start: at,
+                end: self.end,
+            },
+        )
+    }
+
+    /// 两个区间的交集，如果不重合则返回 `None`
+    pub fn intersect(&self, other: &Range<T>) -> Option<Range<T>> {
+        let start = self.start.into().max(other.start.into());
+        let end = self.end.into().min(other.end.into());
+        if start < end {
+            Some(Range::from(start..end))
+        } else {
+            None
+        }
+    }
+}
+
+impl Range<VirtualAddress> {
+    /// 得到覆盖这段地址的虚拟页号区间
+    ///
+    /// 起始地址向下取整，结束地址向上取整，因此首尾不对齐的页面也会被包括在内。
+    /// 例如 `0x1800..0x2001` 会得到页号 `1..3`。
+    pub fn to_page_range(&self) -> Range<VirtualPageNumber> {
+        Range::from(VirtualPageNumber::floor(self.start)..VirtualPageNumber::ceil(self.end))
+    }
+}
+
+impl Range<VirtualPageNumber> {
+    /// 将区间拆为三段：开头不足 2M 对齐的 4K 页面、中间 2M 对齐的整段、结尾剩余的 4K 页面
+    ///
+    /// 中间一段的起止都是 512 页的倍数，可以全部用 2M 大页映射。区间中没有完整的 2M 对齐部分时，
+    /// 整个区间都作为开头返回，其余两段为空。例如 `100..1500` 得到 `100..512`、`512..1024` 和 `1024..1500`。
+    pub fn split_for_huge(&self) -> (Self, Self, Self) {
+        const HUGE_PAGES: usize = super::config::PAGE_SIZE / 8;
+        let middle_start = (self.start.0 + HUGE_PAGES - 1) / HUGE_PAGES * HUGE_PAGES;
+        let middle_end = self.end.0 / HUGE_PAGES * HUGE_PAGES;
+        if middle_start >= middle_end {
+            let empty = Range::from(self.end..self.end);
+            return (*self, empty, empty);
+        }
+        (
+            Range::from(self.start.0..middle_start),
+            Range::from(middle_start..middle_end),
+            Range::from(middle_end..self.end.0),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn overlap_and_contains() {
+        let range = Range::<VirtualPageNumber>::from(10..20);
+        assert!(range.overlap_with(&Range::from(19..30)));
+        // 区间是左闭右开的，只在端点处相接不算重合
+        assert!(!range.overlap_with(&Range::from(20..30)));
+        assert!(!range.overlap_with(&Range::from(0..10)));
+        assert!(range.contains(VirtualPageNumber(10)));
+        assert!(!range.contains(VirtualPageNumber(20)));
+        assert_eq!(range.len(), 10);
+        assert_eq!(range.get(3), VirtualPageNumber(13));
+    }
+
+    #[test]
+    fn iterate_both_ways() {
+        let range = Range::<VirtualPageNumber>::from(3..6);
+        let forward: Vec<usize> = range.iter().map(|vpn| vpn.0).collect();
+        let backward: Vec<usize> = range.iter_rev().map(|vpn| vpn.0).collect();
+        assert_eq!(forward, [3, 4, 5]);
+        assert_eq!(backward, [5, 4, 3]);
+    }
+
+    #[test]
+    fn split_and_intersect() {
+        let range = Range::<VirtualPageNumber>::from(10..20);
+        assert_eq!(
+            range.split_at(VirtualPageNumber(15)),
+            (Range::from(10..15), Range::from(15..20))
+        );
+        let (empty, whole) = range.split_at(VirtualPageNumber(10));
+        assert_eq!(empty.len(), 0);
+        assert_eq!(whole, range);
+        assert_eq!(
+            range.intersect(&Range::from(15..30)),
+            Some(Range::from(15..20))
+        );
+        assert_eq!(range.intersect(&Range::from(20..30)), None);
+    }
+
+    #[test]
+    fn page_range_rounds_outwards() {
+        assert_eq!(
+            Range::<VirtualAddress>::from(0x1800..0x2001).to_page_range(),
+            Range::from(1..3)
+        );
+        assert_eq!(
+            Range::<VirtualAddress>::from(0x1000..0x2000).to_page_range(),
+            Range::from(1..2)
+        );
+    }
+
+    #[test]
+    fn split_for_huge() {
+        assert_eq!(
+            Range::<VirtualPageNumber>::from(100..1500).split_for_huge(),
+            (
+                Range::from(100..512),
+                Range::from(512..1024),
+                Range::from(1024..1500)
+            )
+        );
+        // 没有完整的 2M 对齐部分时，整个区间作为开头返回
+        let range = Range::<VirtualPageNumber>::from(100..600);
+        let (head, middle, tail) = range.split_for_huge();
+        assert_eq!(head, range);
+        assert_eq!((middle.len(), tail.len()), (0, 0));
+    }
 }
@@ -0,0 +1,292 @@
+//! 启动时在 QEMU 中运行的内存管理自检
+//!
+//! 页表和物理帧只能在目标机器上使用，无法通过 `cargo test` 检查，
+//! 因此开启 `self_test` feature 时由 `rust_main` 在启动完成后调用 [`run`]，任何一项检查失败都会 panic。
+//! 每一项检查结束时都会释放它用到的帧，之后的检查从相同的状态开始。
+
+use super::frame::FrameTracker;
+use super::mapping::{OomAction, OomHandler, HEAP_STACK_COLLISION};
+use super::*;
+use alloc::{format, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// 依次运行所有自检
+pub fn run() {
+    unmap_churn();
+    heap_stack_collision();
+    huge_pages();
+    format_maps();
+    split_coalesce();
+    vmalloc_window();
+    user_default_flags();
+    oom_retry();
+    println!("mod memory self test passed");
+}
+
+/// 反复映射、取消映射随机的区间，之后空闲帧数应回到开始时的值
+///
+/// 取消映射时释放变为空的页表，因此页表不会随着映射次数累积
+fn unmap_churn() {
+    // 在从这个页号开始的 1G 用户空间内选择区间，跨越多个页表
+    const BASE: usize = 0x1000;
+    const PAGES: usize = 0x4_0000;
+    let mut memory_set = MemorySet::new_kernel().unwrap();
+    let baseline = FRAME_ALLOCATOR.lock().free_count();
+    // 线性同余生成器，每次启动得到相同的序列，失败时可以复现
+    let mut seed = 1usize;
+    for _ in 0..10_000 {
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        let start = BASE + (seed >> 33) % PAGES;
+        let len = 1 + (seed >> 29) % 8;
+        let segment = Segment {
+            map_type: MapType::Framed,
+            range: Range::<VirtualPageNumber>::from(start..start + len).into(),
+            flags: Flags::READABLE | Flags::WRITABLE | Flags::USER,
+        };
+        memory_set.add_segment(segment.clone(), None).unwrap();
+        memory_set.remove_segment(&segment).unwrap();
+    }
+    assert_eq!(
+        FRAME_ALLOCATOR.lock().free_count(),
+        baseline,
+        "frames leaked by map / unmap churn"
+    );
+}
+
+/// 堆一直增长到栈所在的位置时返回 [`HEAP_STACK_COLLISION`]，而不是覆盖栈，或者在映射时才发现页面已经映射
+fn heap_stack_collision() {
+    // 堆和栈之间只有 8 个页面
+    let heap_start = VirtualAddress(USER_STACK_TOP.0 - 8 * PAGE_SIZE);
+    let mut space = AddressSpace::new(MemorySet::new_kernel().unwrap(), heap_start, true);
+    space.map_stack(4).unwrap();
+    // 紧挨着栈底的堆仍然可以映射
+    let stack_bottom = VirtualAddress(heap_start.0 + 4 * PAGE_SIZE);
+    assert_eq!(space.brk(stack_bottom), Ok(stack_bottom));
+    let segments = space.memory_set.segments.len();
+    assert_eq!(space.brk(stack_bottom + 1), Err(HEAP_STACK_COLLISION));
+    assert_eq!(space.map_stack(1), Err(HEAP_STACK_COLLISION));
+    // 失败时没有添加任何字段，堆顶也没有改变
+    assert_eq!(space.memory_set.segments.len(), segments);
+    assert_eq!(space.heap_end(), stack_bottom);
+}
+
+/// 2M 对齐的区间使用大页映射，不需要最后一级页表
+fn huge_pages() {
+    let mut memory_set = MemorySet::new_kernel().unwrap();
+    let before = memory_set.mapping.page_table_stats();
+    // 两个 2M 大页，前后各有一个 4K 页面
+    memory_set
+        .map_alloc_huge(
+            Range::from(0x3_ffff..0x4_0401),
+            Flags::READABLE | Flags::WRITABLE | Flags::USER,
+        )
+        .unwrap();
+    let after = memory_set.mapping.page_table_stats();
+    // 首尾的 4K 页面各需要一个最后一级页表，它们的二级页表也不同
+    assert_eq!(after[1], before[1] + 2, "{:?} -> {:?}", before, after);
+    assert_eq!(after[2], before[2] + 2, "{:?} -> {:?}", before, after);
+    assert_eq!(memory_set.allocated_pairs.len(), 1026);
+}
+
+/// [`MemorySet::format_maps`] 按地址排序输出每个字段，相邻的、权限相同的线性映射字段合并为一行
+fn format_maps() {
+    extern "C" {
+        fn data_start();
+    }
+    let mut memory_set = MemorySet::new_kernel().unwrap();
+    // 故意倒序添加
+    memory_set
+        .map_lazy(
+            Range::from(0x2000..0x2004),
+            Flags::READABLE | Flags::EXECUTABLE | Flags::USER,
+        )
+        .unwrap();
+    memory_set
+        .add_segment(
+            Segment {
+                map_type: MapType::Framed,
+                range: Range::<VirtualPageNumber>::from(0x1000..0x1002).into(),
+                flags: Flags::READABLE | Flags::WRITABLE | Flags::USER,
+            },
+            None,
+        )
+        .unwrap();
+    let maps = memory_set.format_maps();
+    // 内核的字段都在高半部分，排在用户的字段之后
+    let user_lines: Vec<&str> = maps
+        .lines()
+        .take_while(|line| !line.starts_with("ffff"))
+        .collect();
+    assert_eq!(
+        user_lines,
+        [
+            "01000000-01002000 rw-p 00000000 00:00 0 [framed]",
+            "02000000-02004000 r-xp 00000000 00:00 0 [lazy]",
+        ]
+    );
+    // .data、.bss 和剩余的内存是相邻的可读写线性映射，合并为一行
+    let kernel_rw = format!(
+        "{:08x}-{:08x} rw-s 00000000 00:00 0 [linear]",
+        data_start as usize,
+        VirtualAddress::from(memory_end()).0
+    );
+    assert!(maps.lines().any(|line| line == kernel_rw), "{}", maps);
+}
+
+/// 一个字段被拆成相邻的几段之后，[`MemorySet::coalesce`] 把它们合并回原来的字段，但不会合并权限不同的字段
+fn split_coalesce() {
+    let mut memory_set = MemorySet::new_kernel().unwrap();
+    let framed = Segment {
+        map_type: MapType::Framed,
+        range: Range::<VirtualPageNumber>::from(0x1000..0x1008).into(),
+        flags: Flags::READABLE | Flags::WRITABLE | Flags::USER,
+    };
+    memory_set.add_segment(framed.clone(), None).unwrap();
+    memory_set
+        .map_alloc_with(Range::from(0x1008..0x100c), |vpn| {
+            Flags::READABLE | Flags::USER | Flags::executable(vpn.0 % 2 == 0)
+        })
+        .unwrap();
+    let with_flags = memory_set.segments.pop().unwrap();
+    // 紧接着的只读字段权限不同，不应被合并
+    let read_only = Segment {
+        map_type: MapType::Framed,
+        range: Range::<VirtualPageNumber>::from(0x100c..0x1010).into(),
+        flags: Flags::READABLE | Flags::USER,
+    };
+    memory_set.add_segment(read_only.clone(), None).unwrap();
+
+    // 模拟修改权限或部分取消映射留下的相邻小字段，拆开之后打乱顺序
+    memory_set.segments.retain(|segment| *segment != framed);
+    for &(start, end) in [(0x1004, 0x1008), (0x1000, 0x1001), (0x1001, 0x1004)].iter() {
+        memory_set.segments.push(Segment {
+            range: Range::<VirtualPageNumber>::from(start..end).into(),
+            ..framed.clone()
+        });
+    }
+    let page_flags = match &with_flags.map_type {
+        MapType::FramedWithFlags(page_flags) => page_flags.clone(),
+        _ => unreachable!(),
+    };
+    for &(start, end) in [(0x100a, 0x100c), (0x1008, 0x100a)].iter() {
+        memory_set.segments.push(Segment {
+            map_type: MapType::FramedWithFlags(page_flags[start - 0x1008..end - 0x1008].to_vec()),
+            range: Range::<VirtualPageNumber>::from(start..end).into(),
+            flags: with_flags.flags,
+        });
+    }
+
+    memory_set.coalesce();
+    let user_segments: Vec<&Segment> = memory_set
+        .segments
+        .iter()
+        .filter(|segment| segment.flags.contains(Flags::USER))
+        .collect();
+    assert_eq!(
+        user_segments,
+        [&framed, &with_flags, &read_only],
+        "{:?}",
+        memory_set
+    );
+}
+
+/// [`MemorySet::new_kernel`] 之后，内核动态映射区域中既没有字段也没有页表项，第一次分配从区域的起始处开始
+fn vmalloc_window() {
+    let mut memory_set = MemorySet::new_kernel().unwrap();
+    let window = Range::from(
+        VirtualPageNumber::floor(KERNEL_VMALLOC_BASE)..VirtualPageNumber::floor(KERNEL_VMALLOC_END),
+    );
+    assert!(!memory_set.overlap_with(window));
+    if let Some(step) = memory_set
+        .mapping
+        .walker()
+        .find(|step| window.contains(step.vpn))
+    {
+        panic!("vmalloc window is mapped by {:x?}", step);
+    }
+    let range = memory_set.alloc_anywhere(4, Flags::RW).unwrap();
+    assert_eq!(range.start, window.start);
+}
+
+/// 用户地址空间中分配的页面都带有 USER，即使调用者没有指定
+fn user_default_flags() {
+    let mut memory_set = MemorySet::new_user().unwrap();
+    memory_set
+        .add_segment(
+            Segment {
+                map_type: MapType::Framed,
+                range: Range::<VirtualPageNumber>::from(0x1000..0x1004).into(),
+                flags: Flags::READABLE | Flags::WRITABLE,
+            },
+            None,
+        )
+        .unwrap();
+    memory_set
+        .map_alloc_with(Range::from(0x1004..0x1008), |vpn| {
+            Flags::READABLE | Flags::executable(vpn.0 % 2 == 0)
+        })
+        .unwrap();
+    assert_eq!(memory_set.allocated_pairs.len(), 8);
+    for (vpn, _frame) in memory_set.allocated_pairs.iter() {
+        let flags = memory_set.mapping.entry_flags(*vpn).unwrap();
+        assert!(
+            flags.contains(Flags::USER),
+            "user page {:x?} is mapped without USER: {:?}",
+            vpn,
+            flags
+        );
+    }
+}
+
+/// 没有空闲的帧时，[`OomHandler`] 释放一个帧并要求重试，映射随之成功，新映射的页面可以通过虚拟地址读写
+fn oom_retry() {
+    /// 持有一些帧，每次被调用时释放其中一个
+    struct ReleaseOne {
+        pool: Mutex<Vec<FrameTracker>>,
+        calls: AtomicUsize,
+    }
+    impl OomHandler for ReleaseOne {
+        fn on_oom(&self, _needed: usize) -> OomAction {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match self.pool.lock().pop() {
+                Some(_frame) => OomAction::Retry,
+                None => OomAction::Fail,
+            }
+        }
+    }
+
+    let mut memory_set = MemorySet::new_kernel().unwrap();
+    // 取走所有剩余的帧，交给处理方式持有
+    let mut pool = Vec::new();
+    while let Ok(frame) = FRAME_ALLOCATOR.lock().alloc() {
+        pool.push(frame);
+    }
+    let handler = Arc::new(ReleaseOne {
+        pool: Mutex::new(pool),
+        calls: AtomicUsize::new(0),
+    });
+    memory_set.mapping.set_oom_handler(handler.clone());
+    let va = VirtualAddress(0x100_0000);
+    memory_set
+        .add_segment(
+            Segment {
+                map_type: MapType::Framed,
+                range: Range::from(va..va + PAGE_SIZE),
+                flags: Flags::READABLE | Flags::WRITABLE | Flags::USER,
+            },
+            None,
+        )
+        .unwrap();
+    // 页面本身和它的两级页表各需要一个帧，每次都要等处理方式释放一个
+    assert_eq!(handler.calls.load(Ordering::Relaxed), 3);
+    // 切换到这个映射，通过虚拟地址访问新映射的页面
+    let value = memory_set.with_active(|| unsafe {
+        let pointer = va.0 as *mut usize;
+        pointer.write_volatile(0x5a5a_5a5a);
+        pointer.read_volatile()
+    });
+    assert_eq!(value, 0x5a5a_5a5a);
+}
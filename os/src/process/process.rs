@@ -41,12 +41,20 @@ impl Process {
     ) -> MemoryResult<Range<VirtualAddress>> {
         // memory_set 只能按页分配，所以让 size 向上取整页
         let alloc_size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
-        // 从 memory_set 中找一段不会发生重叠的空间
-        let mut range = Range::<VirtualAddress>::from(0x1000000..0x1000000 + alloc_size);
+        // 从 memory_set 中找一段不会发生重叠的空间，用户进程和内核进程分别在地址空间的两半中寻找
+        let base = if self.is_user {
+            0x1000000
+        } else {
+            KERNEL_ADDRESS_MIN.0
+        };
+        let mut range = Range::<VirtualAddress>::from(base..base + alloc_size);
         while self.memory_set.overlap_with(range.into()) {
             range.start += alloc_size;
             range.end += alloc_size;
         }
+        if self.is_user && range.end > USER_ADDRESS_MAX {
+            return Err("no free space in user address space");
+        }
         // 分配物理页面，建立映射
         self.memory_set.add_segment(
             Segment {
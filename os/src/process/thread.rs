@@ -72,9 +72,7 @@ impl Thread {
         arguments: Option<&[usize]>,
     ) -> MemoryResult<Arc<Thread>> {
         // 让所属进程分配并映射一段空间，作为线程的栈
-        let stack = process
-            .write()
-            .alloc_page_range(STACK_SIZE, Flags::READABLE | Flags::WRITABLE)?;
+        let stack = process.write().alloc_page_range(STACK_SIZE, Flags::RW)?;
 
         // 构建线程的 Context
         let context = Context::new(
@@ -2,6 +2,9 @@
 // 目前还不会用到全部的 SBI 调用，暂时允许未使用的变量或函数
 #![allow(unused)]
 
+use crate::memory::{TlbShootdown, VirtualAddress, VirtualPageNumber, PAGE_SIZE};
+use core::convert::TryFrom;
+
 /// SBI 调用
 #[inline(always)]
 fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
@@ -50,3 +53,34 @@ pub fn shutdown() -> ! {
 pub fn set_timer(time: usize) {
     sbi_call(SBI_SET_TIMER, time, 0, 0);
 }
+
+/// 通过 SBI 的 remote sfence.vma 让其他核刷新 TLB
+///
+/// 带 ASID 的调用需要四个参数，这里统一刷新所有地址空间，范围更大但结果同样正确。
+/// 旧版 SBI 的位图只能表示 0 到 63 号核，`harts` 中有更大的核号时 panic
+pub struct SbiShootdown;
+
+impl TlbShootdown for SbiShootdown {
+    fn remote_flush(&self, harts: &[usize], vpn: Option<VirtualPageNumber>, _asid: Option<u16>) {
+        // 旧版 SBI 用位图表示目标核，参数为位图的地址。位图只有一个字，也没有 hart_mask_base，
+        // 无法表示的核号不能被静默地跳过，否则那个核会继续使用旧的页表项
+        let hart_mask = harts.iter().fold(0usize, |mask, &hart| {
+            let bit = u32::try_from(hart)
+                .ok()
+                .and_then(|shift| 1usize.checked_shl(shift))
+                .expect("hart id does not fit in the legacy SBI hart mask");
+            mask | bit
+        });
+        let (start, size) = match vpn {
+            Some(vpn) => (VirtualAddress::from(vpn).0, PAGE_SIZE),
+            // 起始地址和大小均为 0 表示刷新全部
+            None => (0, 0),
+        };
+        sbi_call(
+            SBI_REMOTE_SFENCE_VMA,
+            &hart_mask as *const usize as usize,
+            start,
+            size,
+        );
+    }
+}